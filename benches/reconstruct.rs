@@ -0,0 +1,140 @@
+// Reconstructing one file with the reduction tree of `reconstruct` against
+// appending all of its fragments to the first one by one with `cat`,
+// for fragment sets of varying counts and sizes.
+//
+// Then a mixed workload of small and large targets reconstructed at the same time,
+// with the fixed default fan-out against `Config::adaptive_fanout`.
+//
+// Run with `cargo bench`. Every iteration works on fresh fragments, whose writing
+// is not timed, so that the count where the tree starts to pay off is visible.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mtreconstruct::split::fragment_path;
+use mtreconstruct::{cat, reconstruct, Config, Reconstructor, DELIMITER_DEFAULT};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Fragment counts and sizes, in bytes, of the cases.
+const COUNTS: &[usize] = &[4, 32, 256, 2048];
+const SIZES: &[usize] = &[64, 4096, 256 * 1024];
+// Cases holding more than this in total are left out.
+const MAX_TOTAL: usize = 256 * 1024 * 1024;
+// Fragment counts and sizes of the targets of the mixed workload.
+const MIXED: &[(usize, usize)] = &[
+    (2, 4 * 1024 * 1024),
+    (8, 1024 * 1024),
+    (64, 64 * 1024),
+    (1024, 16 * 1024),
+    (8192, 1024),
+];
+
+fn sequential_and_tree(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    let config = Config::default();
+    for &size in SIZES {
+        let mut group = c.benchmark_group(format!("{}-byte fragments", size));
+        group.sample_size(10);
+        for &count in COUNTS {
+            if count * size > MAX_TOTAL {
+                continue;
+            }
+            group.throughput(Throughput::Bytes((count * size) as u64));
+            let output = dir.join(format!("{}x{}.bin", count, size));
+            group.bench_function(BenchmarkId::new("sequential", count), |b| {
+                b.iter_custom(|iters| {
+                    timed(iters, || {
+                        let fragments = split(&output, count, size)?;
+                        let started = Instant::now();
+                        cat(&fragments, false)?;
+                        let elapsed = started.elapsed();
+                        fs::remove_file(&fragments[0])?;
+                        Ok(elapsed)
+                    })
+                })
+            });
+            group.bench_function(BenchmarkId::new("tree", count), |b| {
+                b.iter_custom(|iters| {
+                    timed(iters, || {
+                        let fragments = split(&output, count, size)?;
+                        let started = Instant::now();
+                        reconstruct(&output, &fragments, &config)?;
+                        let elapsed = started.elapsed();
+                        fs::remove_file(&output)?;
+                        Ok(elapsed)
+                    })
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+fn mixed_fanout(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    let fixed = Config::default();
+    let adaptive = Config {
+        adaptive_fanout: true,
+        ..fixed.clone()
+    };
+    let mut group = c.benchmark_group(format!("mixed, {} targets", MIXED.len()));
+    group.sample_size(10);
+    for (name, config) in [("fixed", &fixed), ("adaptive", &adaptive)] {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| timed(iters, || mixed(dir, config)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sequential_and_tree, mixed_fanout);
+criterion_main!(benches);
+
+// Total time of `iters` calls of `run`, each returning the time it measured.
+fn timed(iters: u64, mut run: impl FnMut() -> io::Result<Duration>) -> Duration {
+    (0..iters).map(|_| run().unwrap()).sum()
+}
+
+// Time to reconstruct every target of MIXED at once with `config`, one thread each.
+fn mixed(dir: &Path, config: &Config) -> io::Result<Duration> {
+    let targets = MIXED
+        .iter()
+        .map(|&(count, size)| {
+            let output = dir.join(format!("mixed-{}x{}.bin", count, size));
+            Ok((output.clone(), split(&output, count, size)?))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let reconstructor = Arc::new(Reconstructor::new(config.clone()));
+    let started = Instant::now();
+    let handles = targets
+        .iter()
+        .cloned()
+        .map(|(output, fragments)| {
+            let reconstructor = Arc::clone(&reconstructor);
+            std::thread::spawn(move || reconstructor.reconstruct(&output, &fragments))
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().expect("reconstruction panicked")?;
+    }
+    let elapsed = started.elapsed();
+    for (output, _) in targets {
+        fs::remove_file(output)?;
+    }
+    Ok(elapsed)
+}
+
+// Write `count` fragments of `size` bytes each for `output`, returning their paths in order.
+fn split(output: &Path, count: usize, size: usize) -> io::Result<Vec<PathBuf>> {
+    (0..count)
+        .map(|i| {
+            let path = fragment_path(output, DELIMITER_DEFAULT, i);
+            fs::write(&path, vec![i as u8; size])?;
+            Ok(path)
+        })
+        .collect()
+}
@@ -0,0 +1,149 @@
+//! Failures of a reconstruction that callers may want to tell apart.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Why files could not be concatenated or reconstructed.
+///
+/// Inside the library these travel as the payload of an `io::Error`, which
+/// `From<io::Error>` unwraps again, so that any `io::Result` can be `?`-ed.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ReconstructError {
+    /// A fragment was gone when it was to be read.
+    MissingFragment { path: PathBuf },
+    /// The file fragments are appended to in place was gone, e.g. renamed to the
+    /// output by an earlier run. Not retried.
+    MissingLeader { path: PathBuf },
+    /// A fragment does not hold the number of bytes it was named or listed with.
+    SizeMismatch {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    /// A fragment or output does not have the checksum recorded for it.
+    ChecksumMismatch {
+        path: PathBuf,
+        /// e.g. CRC32
+        algorithm: &'static str,
+        expected: String,
+        actual: String,
+    },
+    /// The output exists and `Config::existing` says not to replace it.
+    AlreadyExists { path: PathBuf },
+    /// The output could not be copied to its destination on another filesystem.
+    CrossDeviceRename {
+        from: PathBuf,
+        to: PathBuf,
+        source: io::Error,
+    },
+    /// Stopped before completion, e.g. by a signal.
+    Stopped,
+    /// Not done within `Config::target_timeout`.
+    TimedOut { timeout: Duration },
+    /// Any other I/O error.
+    Io(io::Error),
+}
+
+impl ReconstructError {
+    /// The closest `io::ErrorKind`, e.g. `NotFound` for a missing fragment.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            ReconstructError::MissingFragment { .. } => io::ErrorKind::NotFound,
+            ReconstructError::MissingLeader { .. } => io::ErrorKind::NotFound,
+            ReconstructError::SizeMismatch { .. } => io::ErrorKind::InvalidData,
+            ReconstructError::ChecksumMismatch { .. } => io::ErrorKind::InvalidData,
+            ReconstructError::AlreadyExists { .. } => io::ErrorKind::AlreadyExists,
+            ReconstructError::CrossDeviceRename { source, .. } => source.kind(),
+            ReconstructError::Stopped => io::ErrorKind::Interrupted,
+            ReconstructError::TimedOut { .. } => io::ErrorKind::TimedOut,
+            ReconstructError::Io(error) => error.kind(),
+        }
+    }
+}
+
+impl fmt::Display for ReconstructError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReconstructError::MissingFragment { path } => {
+                write!(f, "{}: fragment not found", path.display())
+            }
+            ReconstructError::MissingLeader { path } => write!(
+                f,
+                "cannot open leader {} for appending: {:?}",
+                path.display(),
+                io::ErrorKind::NotFound
+            ),
+            ReconstructError::SizeMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: expected {} bytes, got {}",
+                path.display(),
+                expected,
+                actual
+            ),
+            ReconstructError::ChecksumMismatch {
+                path,
+                algorithm,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} mismatch for {}: expected {}, got {}",
+                algorithm,
+                path.display(),
+                expected,
+                actual
+            ),
+            ReconstructError::AlreadyExists { path } => {
+                write!(f, "{} already exists", path.display())
+            }
+            ReconstructError::CrossDeviceRename { from, to, source } => write!(
+                f,
+                "cannot move {} to {} on another filesystem: {}",
+                from.display(),
+                to.display(),
+                source
+            ),
+            ReconstructError::Stopped => write!(f, "stopped before completion"),
+            ReconstructError::TimedOut { timeout } => {
+                write!(f, "not reconstructed within {:?}", timeout)
+            }
+            ReconstructError::Io(error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error for ReconstructError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReconstructError::CrossDeviceRename { source, .. } => Some(source),
+            ReconstructError::Io(error) => error.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReconstructError {
+    fn from(error: io::Error) -> Self {
+        match error.downcast::<ReconstructError>() {
+            Ok(error) => error,
+            Err(error) => ReconstructError::Io(error),
+        }
+    }
+}
+
+impl From<ReconstructError> for io::Error {
+    fn from(error: ReconstructError) -> Self {
+        match error {
+            ReconstructError::Io(error) => error,
+            error => io::Error::new(error.kind(), error),
+        }
+    }
+}
@@ -0,0 +1,365 @@
+//! Record of the fragments appended to a leader in place and removed, so that a
+//! reconstruction interrupted between the two and started over appends none twice.
+//!
+//! A record is the length of the leader after a fragment was appended, the leader,
+//! the fragment and the identity of the leader, each followed by a NUL. A record
+//! without a fragment gives the length of the leader before anything was appended
+//! to it in place. The identity tells a journal left by an interrupted run from one
+//! whose files have since been replaced by others of the same names.
+
+use crate::path_from_bytes;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Journal of one reconstructed file, shared by all of its tasks.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    path: PathBuf,
+    fsync: bool,
+    // Largest length recorded for every leader, loaded by `recover` and kept
+    // up to date by `record`, so that the journal is only read once.
+    lengths: Arc<Mutex<HashMap<PathBuf, u64>>>,
+}
+
+/// One fragment appended to a leader, or the leader before anything was.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Length of the leader afterwards.
+    pub len: u64,
+    pub leader: PathBuf,
+    pub fragment: Option<PathBuf>,
+    /// Identity of the leader, see `file_id`.
+    pub id: Option<String>,
+}
+
+/// What tells the file of `metadata` from a later one of the same name: the device
+/// and inode on Unix, the creation time elsewhere. None where neither is known.
+pub fn file_id(metadata: &fs::Metadata) -> Option<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some(format!("{}:{}", metadata.dev(), metadata.ino()))
+    }
+    #[cfg(not(unix))]
+    {
+        let created = metadata.created().ok()?;
+        let since_epoch = created.duration_since(std::time::UNIX_EPOCH).ok()?;
+        Some(since_epoch.as_nanos().to_string())
+    }
+}
+
+impl Journal {
+    /// Journal kept at `path`, synced to disk on every write if `fsync`.
+    pub fn new(path: PathBuf, fsync: bool) -> Journal {
+        Journal {
+            path,
+            fsync,
+            lengths: Arc::default(),
+        }
+    }
+
+    /// Where the journal is kept.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add `records` at once, before the fragments they name are removed.
+    pub fn record(&self, records: &[Record]) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        for record in records {
+            bytes.extend(record.len.to_string().as_bytes());
+            bytes.push(0);
+            bytes.extend(record.leader.as_os_str().as_encoded_bytes());
+            bytes.push(0);
+            if let Some(fragment) = &record.fragment {
+                bytes.extend(fragment.as_os_str().as_encoded_bytes());
+            }
+            bytes.push(0);
+            if let Some(id) = &record.id {
+                bytes.extend(id.as_bytes());
+            }
+            bytes.push(0);
+        }
+        // One write, so that the tasks of a file appending at the same time do not interleave.
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&bytes)?;
+        if self.fsync {
+            file.sync_data()?;
+        }
+        let mut lengths = self.lengths.lock().unwrap();
+        for record in records {
+            let len = lengths.entry(record.leader.clone()).or_default();
+            *len = (*len).max(record.len);
+        }
+        Ok(())
+    }
+
+    /// Every record so far, none if there is no journal.
+    /// A record cut short by an interruption is ignored.
+    pub fn read(&self) -> io::Result<Vec<Record>> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error),
+        };
+        let fields = bytes.split(|&b| b == 0).collect::<Vec<_>>();
+        // The last field is whatever follows the last NUL.
+        let fields = &fields[..fields.len() - 1];
+        fields
+            .chunks_exact(4)
+            .map(|record| {
+                let invalid = || {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: invalid record", self.path.display()),
+                    )
+                };
+                let len = std::str::from_utf8(record[0])
+                    .ok()
+                    .and_then(|len| len.parse().ok())
+                    .ok_or_else(invalid)?;
+                let leader = path_from_bytes(record[1]).ok_or_else(invalid)?;
+                let fragment = match record[2] {
+                    [] => None,
+                    fragment => Some(path_from_bytes(fragment).ok_or_else(invalid)?),
+                };
+                let id = match record[3] {
+                    [] => None,
+                    id => Some(String::from_utf8(id.to_vec()).map_err(|_| invalid())?),
+                };
+                Ok(Record {
+                    len,
+                    leader,
+                    fragment,
+                    id,
+                })
+            })
+            .collect()
+    }
+
+    /// Largest length recorded for `leader` by `recover` or since, if any.
+    pub fn length(&self, leader: &Path) -> Option<u64> {
+        self.lengths.lock().unwrap().get(leader).copied()
+    }
+
+    /// Fragments recorded as appended, whether or not they are still there.
+    pub fn fragments(&self) -> io::Result<HashSet<PathBuf>> {
+        Ok(self
+            .read()?
+            .into_iter()
+            .filter_map(|r| r.fragment)
+            .collect())
+    }
+
+    /// Undo what an interrupted run left half done: cut every leader back to the
+    /// length last recorded and remove the recorded fragments still there.
+    /// Returns the recorded fragments, whose content is in a leader by now.
+    /// Fails without touching anything if a leader is not the file recorded.
+    pub fn recover(&self) -> io::Result<HashSet<PathBuf>> {
+        let records = self.read()?;
+        let fragments = records
+            .iter()
+            .filter_map(|r| r.fragment.clone())
+            .collect::<HashSet<_>>();
+        let mut leaders: HashMap<&Path, (u64, Option<&str>)> = HashMap::new();
+        for record in &records {
+            let (len, id) = leaders.entry(&record.leader).or_default();
+            *len = (*len).max(record.len);
+            *id = id.or(record.id.as_deref());
+        }
+        let stale = |leader: &Path| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is not the file recorded in {}, which is left from another run. \
+                    Remove the journal to start over.",
+                    leader.display(),
+                    self.path.display()
+                ),
+            )
+        };
+        let mut cut = Vec::new();
+        for (&leader, &(len, id)) in &leaders {
+            let metadata = match fs::metadata(leader) {
+                Ok(metadata) => metadata,
+                // Appended into another leader since.
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                    if fragments.contains(leader) {
+                        continue;
+                    }
+                    return Err(stale(leader));
+                }
+                Err(error) => return Err(error),
+            };
+            if id.is_some() && file_id(&metadata).as_deref() != id {
+                return Err(stale(leader));
+            }
+            if metadata.len() < len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{}: {} bytes, {} were recorded in {}",
+                        leader.display(),
+                        metadata.len(),
+                        len,
+                        self.path.display()
+                    ),
+                ));
+            }
+            if metadata.len() > len {
+                cut.push((leader, len));
+            }
+        }
+
+        for (leader, len) in cut {
+            log::info!(
+                "Cutting {} back to {} bytes, the rest was appended by an interrupted run",
+                leader.display(),
+                len
+            );
+            fs::OpenOptions::new()
+                .write(true)
+                .open(leader)?
+                .set_len(len)?;
+        }
+        *self.lengths.lock().unwrap() = leaders
+            .iter()
+            .map(|(&leader, &(len, _))| (leader.to_path_buf(), len))
+            .collect();
+        for fragment in &fragments {
+            match fs::remove_file(fragment) {
+                Ok(()) => log::info!("Removed {}, already appended", fragment.display()),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(fragments)
+    }
+
+    /// Remove the journal once the file is reconstructed.
+    pub fn remove(&self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A journal in `dir` recording `fragment` appended to `leader`, which was
+    // `before` bytes long and is as long as it is now afterwards.
+    fn journal_of_one(dir: &Path, leader: &Path, fragment: &Path, before: u64) -> Journal {
+        let journal = Journal::new(dir.join("a.bin.journal"), false);
+        let id = file_id(&fs::metadata(leader).unwrap());
+        let after = fs::metadata(leader).unwrap().len();
+        journal
+            .record(&[
+                Record {
+                    len: before,
+                    leader: leader.to_path_buf(),
+                    fragment: None,
+                    id: id.clone(),
+                },
+                Record {
+                    len: after,
+                    leader: leader.to_path_buf(),
+                    fragment: Some(fragment.to_path_buf()),
+                    id,
+                },
+            ])
+            .unwrap();
+        journal
+    }
+
+    #[test]
+    fn recover_cuts_the_leader_back_and_removes_the_appended_fragments() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader = dir.path().join("a.bin.FRAG-00000");
+        let fragment = dir.path().join("a.bin.FRAG-00001");
+        fs::write(&leader, b"first second").unwrap();
+        fs::write(&fragment, b" second").unwrap();
+        let journal = journal_of_one(dir.path(), &leader, &fragment, 5);
+        // Appended after the last record.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&leader)
+            .unwrap()
+            .write_all(b" half")
+            .unwrap();
+
+        // Another run, which starts from the file.
+        let journal = Journal::new(journal.path().to_path_buf(), false);
+        assert_eq!(journal.length(&leader), None);
+        assert_eq!(
+            journal.recover().unwrap(),
+            HashSet::from([fragment.clone()])
+        );
+        assert_eq!(fs::read(&leader).unwrap(), b"first second");
+        assert!(!fragment.exists());
+        assert_eq!(journal.length(&leader), Some(12));
+    }
+
+    #[test]
+    fn record_keeps_the_lengths_up_to_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path().join("a.bin.journal"), false);
+        let leader = dir.path().join("a.bin.FRAG-00000");
+        for (len, fragment) in [(3, None), (10, Some("a.bin.FRAG-00001"))] {
+            journal
+                .record(&[Record {
+                    len,
+                    leader: leader.clone(),
+                    fragment: fragment.map(|f| dir.path().join(f)),
+                    id: None,
+                }])
+                .unwrap();
+        }
+        assert_eq!(journal.length(&leader), Some(10));
+        assert_eq!(journal.read().unwrap().len(), 2);
+    }
+
+    // Elsewhere a file created again under the same name may keep its creation time.
+    #[cfg(unix)]
+    #[test]
+    fn recover_leaves_alone_files_replaced_since() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader = dir.path().join("a.bin.FRAG-00000");
+        let fragment = dir.path().join("a.bin.FRAG-00001");
+        fs::write(&leader, b"first second").unwrap();
+        fs::write(&fragment, b" second").unwrap();
+        let journal = journal_of_one(dir.path(), &leader, &fragment, 5);
+        // Split again, then the interrupted run's leader is gone.
+        let kept = dir.path().join("kept");
+        fs::rename(&leader, &kept).unwrap();
+        fs::write(&leader, b"new first fragment").unwrap();
+
+        let journal = Journal::new(journal.path().to_path_buf(), false);
+        let error = journal.recover().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(&leader).unwrap(), b"new first fragment");
+        assert!(fragment.exists());
+    }
+
+    #[test]
+    fn recover_fails_for_a_leader_gone_without_being_appended() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader = dir.path().join("a.bin.FRAG-00000");
+        let fragment = dir.path().join("a.bin.FRAG-00001");
+        fs::write(&leader, b"first second").unwrap();
+        fs::write(&fragment, b" second").unwrap();
+        let journal = journal_of_one(dir.path(), &leader, &fragment, 5);
+        fs::remove_file(&leader).unwrap();
+
+        assert!(journal.recover().is_err());
+        assert!(fragment.exists());
+    }
+}
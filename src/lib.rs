@@ -0,0 +1,2330 @@
+//! Multithread reconstruction of files split into `.FRAG-` fragments.
+//!
+//! Fragments are concatenated by a tree of tasks: every leaf task appends
+//! up to `num_cat_once` fragments to its first fragment, then section tasks
+//! append the leaders of their children until one file is left, which is
+//! renamed to the original file name. The fan-out of either stage can be set
+//! apart with `leaf_fanout` and `section_fanout`.
+
+use flate2::read::GzDecoder;
+use regex::bytes::Regex;
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub mod error;
+pub mod journal;
+pub mod manifest;
+pub mod pool;
+pub mod scan;
+pub mod split;
+
+pub use error::ReconstructError;
+use journal::{Journal, Record};
+use pool::WorkerPool;
+pub use scan::{ScanOptions, VisitDir};
+
+pub const NUM_CAT_ONCE_DEFAULT: usize = 32;
+pub const DELIMITER_DEFAULT: &str = ".FRAG-";
+pub const RETRY_INTERVAL_DEFAULT: Duration = Duration::from_secs(5);
+pub const MAX_RETRY_INTERVAL_DEFAULT: Duration = Duration::from_secs(60);
+pub const MAX_RETRIES_DEFAULT: usize = 10;
+pub const BUFFER_SIZE_DEFAULT: usize = 8 * 1024;
+pub const TMP_SUFFIX_DEFAULT: &str = ".tmp";
+/// Marks the expected size of a fragment after its number.
+pub const SIZE_MARKER: &str = ".sz";
+/// Marks the expected CRC32 of a fragment, in hex, after its number and any size.
+pub const CRC_MARKER: &str = ".crc";
+/// Separates the fragment number from the number of fragments, e.g. .FRAG-00003-of-00100.
+pub const TOTAL_MARKER: &str = "-of-";
+/// Appended to the destination for the journal of the fragments appended in place.
+pub const JOURNAL_SUFFIX: &str = ".journal";
+/// Appended to the output for the sidecar holding its original modification time.
+pub const MTIME_SUFFIX: &str = ".mtime";
+/// Log target of the lines marking the start and the end of every reconstruction.
+pub const PER_TARGET_LOG: &str = "mtreconstruct::target";
+
+/// Settings of a reconstruction.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Maximum number of files concatenated by one task. Must be at least 2.
+    pub num_cat_once: usize,
+    /// Fragments concatenated by one leaf task instead of `num_cat_once`. At least 2.
+    pub leaf_fanout: Option<usize>,
+    /// Leaders merged by one section task instead of `num_cat_once`. At least 2.
+    pub section_fanout: Option<usize>,
+    /// Use `fanout_for` the number of fragments of each file instead of `num_cat_once`.
+    pub adaptive_fanout: bool,
+    /// Time to wait before the first retry of a failed concatenation.
+    /// The wait doubles on every further retry.
+    pub retry_interval: Duration,
+    /// Upper bound of the wait between retries.
+    pub max_retry_interval: Duration,
+    /// Number of retries before a concatenation is given up. 0 retries forever.
+    pub max_retries: usize,
+    /// Leave the fragments on disk. Every fragment, the first one included, is copied
+    /// into new files and none is ever modified, so a run can be started over.
+    pub keep_fragments: bool,
+    /// Compare the output with the digest in `<output>.sha256`, if present.
+    pub verify: bool,
+    /// Directory receiving the reconstructed files instead of the fragment directory.
+    pub output_dir: Option<PathBuf>,
+    /// Write every file straight into `output_dir` by its file name. Otherwise its path
+    /// relative to `root_dir` is kept below `output_dir`.
+    pub flatten: bool,
+    /// Directory the outputs are found in, for `flatten` unset.
+    pub root_dir: PathBuf,
+    /// Number of concatenations running at the same time, over all files.
+    pub jobs: usize,
+    /// Run every concatenation on the thread calling `reconstruct`, one after the
+    /// other, instead of on `jobs` workers. Useful to rule out races when debugging.
+    pub sequential: bool,
+    /// Format the fragments are compressed with.
+    pub decompress: Decompress,
+    /// Assemble into temporary files and only remove the fragments once the output
+    /// is complete, so that an interrupted run can be started over.
+    pub atomic: bool,
+    /// Sync every leader to disk before its fragments are removed,
+    /// and the output directory after the final rename.
+    pub fsync: bool,
+    /// What to do when the output already exists.
+    pub existing: Existing,
+    /// Capacity of the read and write buffers of each concatenation, in bytes.
+    pub buffer_size: usize,
+    /// Check fragments named with a size suffix against that size.
+    pub verify_sizes: bool,
+    /// Write all fragments of a file at their offsets at the same time
+    /// instead of concatenating them. The size of every fragment must be known.
+    pub parallel_write: bool,
+    /// Compute the CRC32 of the output while concatenating, and compare it with
+    /// the one in `<output>.crc32`, if present. Fragments named with a CRC suffix
+    /// are checked against it as they are appended.
+    pub crc32: bool,
+    /// Flush what has been appended to the leader so far at this interval,
+    /// and sync it to disk as well if `fsync` is set.
+    pub checkpoint: Option<Checkpoint>,
+    /// Read uncompressed fragments by mapping them into memory, see `CatOptions::mmap`.
+    pub mmap: bool,
+    /// Most bytes written per second, over all files at once.
+    pub max_bandwidth: Option<u64>,
+    /// Give the output this modification time instead of the time it was written.
+    pub preserve_mtime: Option<MtimeSource>,
+    /// Appended to the name of the files assembled before being renamed to the output.
+    pub tmp_suffix: String,
+    /// Give up a file not reconstructed this long after its first concatenation
+    /// started. Checked between attempts, so a read that never returns still hangs.
+    pub target_timeout: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            num_cat_once: NUM_CAT_ONCE_DEFAULT,
+            leaf_fanout: None,
+            section_fanout: None,
+            adaptive_fanout: false,
+            retry_interval: RETRY_INTERVAL_DEFAULT,
+            max_retry_interval: MAX_RETRY_INTERVAL_DEFAULT,
+            max_retries: MAX_RETRIES_DEFAULT,
+            keep_fragments: false,
+            verify: false,
+            output_dir: None,
+            flatten: true,
+            root_dir: PathBuf::from("."),
+            jobs: jobs_default(),
+            sequential: false,
+            decompress: Decompress::None,
+            atomic: false,
+            fsync: false,
+            existing: Existing::Error,
+            buffer_size: BUFFER_SIZE_DEFAULT,
+            verify_sizes: false,
+            parallel_write: false,
+            crc32: false,
+            checkpoint: None,
+            mmap: false,
+            max_bandwidth: None,
+            preserve_mtime: None,
+            tmp_suffix: String::from(TMP_SUFFIX_DEFAULT),
+            target_timeout: None,
+        }
+    }
+}
+
+/// How often a long concatenation makes what it has appended durable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checkpoint {
+    /// After every so many bytes.
+    Bytes(u64),
+    /// After every so much time.
+    Every(Duration),
+}
+
+/// What to do with a target whose output already exists.
+/// Decided before any fragment is touched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Existing {
+    /// Fail the target.
+    #[default]
+    Error,
+    /// Replace the existing file.
+    Overwrite,
+    /// Leave the existing file and the fragments alone.
+    Skip,
+}
+
+/// Compression of the fragments, undone while concatenating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Decompress {
+    /// Fragments are concatenated as they are.
+    #[default]
+    None,
+    /// Fragments are gzip streams named `<file>.FRAG-00000.gz`.
+    Gzip,
+    /// Fragments are zstd streams named `<file>.FRAG-00000.zst`.
+    Zstd,
+}
+
+impl Decompress {
+    /// Extension following the fragment number.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Decompress::None => "",
+            Decompress::Gzip => ".gz",
+            Decompress::Zstd => ".zst",
+        }
+    }
+
+    // `reader` decoded according to `self`.
+    fn decoder<'a, R: io::BufRead + 'a>(self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Decompress::None => Box::new(reader),
+            Decompress::Gzip => Box::new(GzDecoder::new(reader)),
+            Decompress::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        })
+    }
+}
+
+/// Where the modification time given to a reconstructed file comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MtimeSource {
+    /// Seconds since the Unix epoch, fractions allowed, in `<output>.mtime`.
+    /// Without the sidecar the output keeps the time it was written.
+    Sidecar,
+    /// The modification time of the first fragment before it was appended to.
+    FirstFragment,
+}
+
+/// Fan-out making the tree of `fragments` fragments two levels deep: ceil(sqrt(n))
+/// fragments per leaf task, so that about as many leaders make one section task.
+/// At least 2.
+pub fn fanout_for(fragments: usize) -> usize {
+    let mut root = fragments.isqrt();
+    if root * root < fragments {
+        root += 1;
+    }
+    root.max(2)
+}
+
+/// One job per available CPU.
+pub fn jobs_default() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Pattern matching the encoded bytes of fragment paths, capturing the target
+/// as `target`, the fragment number as `index` and the number of fragments,
+/// if given after `TOTAL_MARKER`, as `total`.
+///
+/// The delimiter is matched literally in the file name, so directories named
+/// like fragments do not matter, and must have something on both sides.
+/// Paths must end with `extension`, which is not part of the fragment number.
+/// e.g. vsi_traverse_-s--l-0.txt.FRAG-00000 -> (vsi_traverse_-s--l-0.txt, 00000)
+/// e.g. weird.FRAG-dir/a.txt.FRAG-00000 -> (weird.FRAG-dir/a.txt, 00000)
+/// e.g. vsi_traverse_-s--l-0.txt.FRAG-00003-of-00100 -> (vsi_traverse_-s--l-0.txt, 00003, 00100)
+/// e.g. vsi_traverse_-s--l-0.txt.FRAG-00003-of-00100.sz1024 -> (vsi_traverse_-s--l-0.txt, 00003, 00100)
+pub fn fragment_pattern(delimiter: &str, extension: &str) -> Result<Regex, regex::Error> {
+    // The size and CRC32 markers may follow the total, they are read from the whole name.
+    let suffix = format!(
+        r"{}(?P<index>[^{}]+?)(?:{}(?P<total>\d+)(?:(?:{}|{})[^{}]*)?)?",
+        regex::escape(delimiter),
+        SEPARATORS,
+        regex::escape(TOTAL_MARKER),
+        regex::escape(SIZE_MARKER),
+        regex::escape(CRC_MARKER),
+        SEPARATORS
+    );
+    suffix_pattern(&suffix, extension)
+}
+
+/// Pattern matching the encoded bytes of files inside a directory named after
+/// their target, named after their fragment number, optionally after `FRAG-`.
+/// The number and the size or CRC32 markers following it, but not `extension`,
+/// are captured as `index`. The reconstructed file replaces the directory.
+/// A file named otherwise, such as one reconstructed before, is not taken for a fragment.
+/// e.g. vsi_traverse_-s--l-0.txt/00003 -> (vsi_traverse_-s--l-0.txt, 00003)
+/// e.g. vsi_traverse_-s--l-0.txt/FRAG-00003 -> (vsi_traverse_-s--l-0.txt, 00003)
+pub fn dir_pattern(extension: &str) -> Result<Regex, regex::Error> {
+    let suffix = format!(
+        r"[{}](?:FRAG-)?(?P<index>\d+(?:(?:{}|{})[^{}]*)?)",
+        SEPARATORS,
+        regex::escape(SIZE_MARKER),
+        regex::escape(CRC_MARKER),
+        SEPARATORS
+    );
+    suffix_pattern(&suffix, extension)
+}
+
+/// Pattern matching the encoded bytes of fragment paths ending with `suffix`,
+/// a regular expression capturing the fragment number as `index`.
+///
+/// The target is everything before the suffix, whether valid UTF-8 or not. The suffix may also capture the
+/// number of fragments of the target as `total`, numbered from 0.
+/// e.g. with _chunk_(?P<index>\d+) vsi_traverse_-s--l-0.txt_chunk_012 -> (vsi_traverse_-s--l-0.txt, 012)
+/// e.g. with \.(?P<index>\d+)of(?P<total>\d+) vsi_traverse_-s--l-0.txt.012of100 -> (vsi_traverse_-s--l-0.txt, 012)
+pub fn suffix_pattern(suffix: &str, extension: &str) -> Result<Regex, regex::Error> {
+    let re = Regex::new(&format!(
+        r"(?s)^(?P<target>(?-u:.*?[^{}]))(?:{}){}$",
+        SEPARATORS,
+        suffix,
+        regex::escape(extension)
+    ))?;
+    if !re.capture_names().any(|name| name == Some("index")) {
+        return Err(regex::Error::Syntax(format!(
+            "{} has no group named index",
+            suffix
+        )));
+    }
+    Ok(re)
+}
+
+// Characters separating the file name from its directory, in a character class.
+const SEPARATORS: &str = if cfg!(windows) { r"/\\" } else { "/" };
+
+/// The path whose `OsStr::as_encoded_bytes` are `bytes`, such as part of a match
+/// of `fragment_pattern`.
+/// Where paths are not bytes, as on Windows, only UTF-8 is accepted.
+pub fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+    }
+    #[cfg(not(unix))]
+    {
+        std::str::from_utf8(bytes).ok().map(PathBuf::from)
+    }
+}
+
+/// `path` without `.` components, repeated separators or a trailing separator,
+/// and with the separators of the platform, so that the same file is always
+/// spelled the same way. `..` is kept, what it refers to depends on symbolic links.
+/// e.g. ./sub//a.txt.FRAG-00000 -> sub/a.txt.FRAG-00000, and . -> the empty path
+pub fn normalize_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+/// Numeric part after the delimiter, without any size suffix.
+/// e.g. vsi_traverse_-s--l-0.txt.FRAG-00012 -> 12
+pub fn fragment_index(re: &Regex, fragment: &Path) -> Option<u64> {
+    let captures = re.captures(fragment.as_os_str().as_encoded_bytes())?;
+    let number = std::str::from_utf8(&captures["index"]).ok()?;
+    let number = number.split_once(SIZE_MARKER).map_or(number, |(n, _)| n);
+    let number = number.split_once(CRC_MARKER).map_or(number, |(n, _)| n);
+    number.parse().ok()
+}
+
+/// Expected size in bytes following `SIZE_MARKER` in the name of a fragment.
+/// e.g. vsi_traverse_-s--l-0.txt.FRAG-00000.sz1048576 -> 1048576
+pub fn fragment_size(fragment: &Path) -> Option<u64> {
+    marked_digits(fragment, SIZE_MARKER)?.parse().ok()
+}
+
+/// Expected CRC32 following `CRC_MARKER` in the name of a fragment.
+/// e.g. vsi_traverse_-s--l-0.txt.FRAG-00000.crc1a2b3c4d -> 0x1a2b3c4d
+pub fn fragment_crc32(fragment: &Path) -> Option<u32> {
+    u32::from_str_radix(marked_digits(fragment, CRC_MARKER)?, 16).ok()
+}
+
+// What follows the last `marker` in `fragment`, up to the next dot.
+// An extension such as .gz may follow.
+fn marked_digits<'a>(fragment: &'a Path, marker: &str) -> Option<&'a str> {
+    let bytes = fragment.as_os_str().as_encoded_bytes();
+    let marker = marker.as_bytes();
+    let start = bytes
+        .windows(marker.len())
+        .rposition(|window| window == marker)?
+        + marker.len();
+    let digits = bytes[start..].split(|&b| b == b'.').next()?;
+    std::str::from_utf8(digits).ok()
+}
+
+/// Number of fragments of the target as captured by `total`, if the pattern has one.
+/// e.g. vsi_traverse_-s--l-0.txt.012of100 -> 100
+pub fn fragment_total(re: &Regex, fragment: &Path) -> Option<u64> {
+    let captures = re.captures(fragment.as_os_str().as_encoded_bytes())?;
+    std::str::from_utf8(captures.name("total")?.as_bytes())
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Fragment numbers missing from the consecutive run starting at zero.
+/// The run ends with the last fragment, or at the largest total if captured.
+/// Fragments without a numeric suffix are not taken into account.
+pub fn missing_indices(re: &Regex, fragments: &[PathBuf]) -> Vec<u64> {
+    let mut indices = fragments
+        .iter()
+        .filter_map(|f| fragment_index(re, f))
+        .collect::<Vec<_>>();
+    indices.sort_unstable();
+    indices.dedup();
+
+    let mut missing = Vec::new();
+    let mut expected = 0;
+    for index in indices {
+        missing.extend(expected..index);
+        expected = index + 1;
+    }
+    let total = fragments.iter().filter_map(|f| fragment_total(re, f)).max();
+    if let Some(total) = total {
+        missing.extend(expected..total);
+    }
+    missing
+}
+
+/// Distinct numbers of fragments named by `fragments`, in increasing order.
+/// Empty if none names one, more than one if they disagree.
+pub fn declared_totals(re: &Regex, fragments: &[PathBuf]) -> Vec<u64> {
+    let mut totals = fragments
+        .iter()
+        .filter_map(|f| fragment_total(re, f))
+        .collect::<Vec<_>>();
+    totals.sort_unstable();
+    totals.dedup();
+    totals
+}
+
+/// Pairs of fragments having the same number.
+/// e.g. vsi_traverse_-s--l-0.txt.FRAG-0002 and vsi_traverse_-s--l-0.txt.FRAG-00002
+pub fn duplicate_fragments<'a>(re: &Regex, fragments: &'a [PathBuf]) -> Vec<(&'a Path, &'a Path)> {
+    let mut indexed = fragments
+        .iter()
+        .filter_map(|f| Some((fragment_index(re, f)?, f.as_path())))
+        .collect::<Vec<_>>();
+    indexed.sort_unstable();
+    indexed
+        .windows(2)
+        .filter(|pair| pair[0].0 == pair[1].0)
+        .map(|pair| (pair[0].1, pair[1].1))
+        .collect()
+}
+
+/// Group fragment paths by the file they reconstruct, in concatenation order.
+/// Paths are normalized first, so that the fragments of a file always share a key.
+/// Paths not matching `re` are ignored.
+pub fn group_fragments<I>(paths: I, re: &Regex) -> HashMap<PathBuf, Vec<PathBuf>>
+where
+    I: IntoIterator<Item = PathBuf>,
+{
+    let mut map: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for i in paths {
+        let i = normalize_path(&i);
+        let Some(captures) = re.captures(i.as_os_str().as_encoded_bytes()) else {
+            continue;
+        };
+        let Some(file) = path_from_bytes(&captures["target"]) else {
+            log::warn!("Skipping {}: cannot tell its target", i.display());
+            continue;
+        };
+        map.entry(file).or_default().push(i);
+    }
+
+    // Order fragments by their number so that FRAG-10 comes after FRAG-9.
+    // Fragments without a numeric suffix fall back to lexical order.
+    for val in map.values_mut() {
+        val.sort_by_cached_key(|f| {
+            let index = fragment_index(re, f);
+            (index.is_none(), index, f.clone())
+        });
+    }
+    map
+}
+
+/// Append the content of file2 to file1.
+/// file1 will be modified.
+/// file2.. will be removed. Those that do not exist are skipped.
+/// If `keep_fragments` is set, file1 is created from scratch and file2.. are left as they are.
+/// Returns the number of bytes appended.
+pub fn cat(files: &[PathBuf], keep_fragments: bool) -> Result<u64, ReconstructError> {
+    cat_with(
+        files,
+        CatOptions {
+            keep_fragments,
+            ..CatOptions::default()
+        },
+    )
+}
+
+/// How `cat_with` appends.
+#[derive(Debug, Clone, Copy)]
+pub struct CatOptions {
+    /// Create file1 from scratch and leave file2.. as they are.
+    pub keep_fragments: bool,
+    /// Decode file2.. before appending them.
+    /// Unless `Decompress::None`, file1 is created from scratch.
+    pub decompress: Decompress,
+    /// Sync file1 to disk before any of file2.. is removed.
+    pub fsync: bool,
+    /// Capacity of the read and write buffers, in bytes.
+    pub buffer_size: usize,
+    /// Fail if a file named with a size suffix does not hold that many bytes.
+    /// file1 is checked before anything is appended to it in place.
+    pub verify_sizes: bool,
+    /// Flush file1 at this interval while appending, syncing it as well if `fsync` is set.
+    pub checkpoint: Option<Checkpoint>,
+    /// Map each of file2.. into memory and write it to file1 at once.
+    /// Empty, compressed and unmappable files are read as usual.
+    /// A file truncated by someone else while mapped kills the process with SIGBUS.
+    pub mmap: bool,
+}
+
+impl Default for CatOptions {
+    fn default() -> Self {
+        CatOptions {
+            keep_fragments: false,
+            decompress: Decompress::None,
+            fsync: false,
+            buffer_size: BUFFER_SIZE_DEFAULT,
+            verify_sizes: false,
+            checkpoint: None,
+            mmap: false,
+        }
+    }
+}
+
+/// Like `cat`, with more `options`.
+/// file2.. are only removed once all of them have been appended. If appending fails,
+/// file1 is cut back to the length it had.
+pub fn cat_with(files: &[PathBuf], options: CatOptions) -> Result<u64, ReconstructError> {
+    Ok(append(files, options, None, None, None)?)
+}
+
+// `cat_with`, feeding `crc` with the whole content of file1 as it is after appending.
+// file1 is read first if it is appended to in place.
+// If file1 is appended to in place, every fragment is recorded in `journal` before
+// it is removed, and file1 is cut back to the length last recorded before appending.
+fn append(
+    files: &[PathBuf],
+    options: CatOptions,
+    mut crc: Option<&mut crc32fast::Hasher>,
+    journal: Option<&Journal>,
+    throttle: Option<&Throttle>,
+) -> io::Result<u64> {
+    let CatOptions {
+        keep_fragments,
+        decompress,
+        fsync,
+        buffer_size,
+        verify_sizes,
+        checkpoint,
+        mmap,
+    } = options;
+    if files.first().is_none_or(|f| f.as_os_str().is_empty()) {
+        return Ok(0);
+    }
+    if files.len() == 1 {
+        // Nothing to append, file1 is the whole result.
+        if let Some(crc) = crc {
+            hash_file(&files[0], buffer_size, crc)?;
+        }
+        return Ok(0);
+    }
+    let fresh_leader = keep_fragments || decompress != Decompress::None;
+    let journal = journal.filter(|_| !fresh_leader);
+    let mut f1 = if fresh_leader {
+        // Start over on every call so that a retry does not append twice.
+        std::fs::File::create(&files[0])?
+    } else {
+        // Not opened for appending, which `copy_file` cannot write to.
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&files[0])
+            .map_err(|error| leader_error(&files[0], error))?
+    };
+    let recorded = journal.and_then(|journal| journal.length(&files[0]));
+    // Recorded with every fragment, so that a stale journal is not taken for this one.
+    let id = match journal {
+        Some(_) => journal::file_id(&f1.metadata()?),
+        None => None,
+    };
+    let mut len = match recorded {
+        // Drop what a failed attempt appended after the last fragment recorded.
+        Some(len) => {
+            f1.set_len(len)?;
+            len
+        }
+        None => f1.metadata()?.len(),
+    };
+    f1.seek(io::SeekFrom::Start(len))?;
+    if verify_sizes && !fresh_leader && recorded.is_none() {
+        check_size(&files[0], len)?;
+    }
+    if let (Some(journal), None) = (journal, recorded) {
+        journal.record(&[Record {
+            len,
+            leader: files[0].clone(),
+            fragment: None,
+            id: id.clone(),
+        }])?;
+    }
+    if let (Some(crc), false) = (crc.as_deref_mut(), fresh_leader) {
+        hash_file(&files[0], buffer_size, crc)?;
+    }
+    let mut buf1 = Checkpointed {
+        writer: std::io::BufWriter::with_capacity(buffer_size, f1),
+        checkpoint,
+        fsync,
+        since_bytes: 0,
+        since: Instant::now(),
+        throttle: throttle.cloned(),
+    };
+    let mut bytes = 0;
+    let mut appended = Vec::new();
+    let start = len;
+
+    let copied = (|| {
+        for file in files.iter().skip(1) {
+            if file.as_os_str().is_empty() {
+                continue;
+            }
+
+            // Skip a file that is gone, e.g. appended by an earlier attempt.
+            // Any other error is worth a retry.
+            let f2 = match std::fs::File::open(file) {
+                Ok(f2) => f2,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(open_error(file, error)),
+            };
+            // Bytes copied within the kernel cannot be paced.
+            let plain =
+                decompress == Decompress::None && checkpoint.is_none() && throttle.is_none();
+            // Compressed fragments are decoded from a stream.
+            let mapping = if mmap && decompress == Decompress::None {
+                map_file(&f2, file)
+            } else {
+                None
+            };
+            let copied = match (&mapping, crc.as_deref_mut()) {
+                (Some(mapping), crc) => {
+                    let bytes = &mapping[..];
+                    if let Some(crc) = crc {
+                        let mut fragment_crc = crc32fast::Hasher::new();
+                        fragment_crc.update(bytes);
+                        check_crc32(file, &fragment_crc)?;
+                        crc.combine(&fragment_crc);
+                    }
+                    buf1.write_all(bytes)?;
+                    bytes.len() as u64
+                }
+                // Nothing needs to see the bytes on their way.
+                (None, None) if plain => {
+                    buf1.flush()?;
+                    copy_file(&f2, buf1.writer.get_ref())?
+                }
+                (None, Some(crc)) => {
+                    let mut buf2 =
+                        decompress.decoder(io::BufReader::with_capacity(buffer_size, f2))?;
+                    let mut fragment_crc = crc32fast::Hasher::new();
+                    let copied = copy_crc32(&mut buf2, &mut buf1, &mut fragment_crc)?;
+                    check_crc32(file, &fragment_crc)?;
+                    crc.combine(&fragment_crc);
+                    copied
+                }
+                (None, None) => {
+                    let mut buf2 =
+                        decompress.decoder(io::BufReader::with_capacity(buffer_size, f2))?;
+                    io::copy(&mut buf2, &mut buf1)?
+                }
+            };
+            if verify_sizes {
+                check_size(file, copied)?;
+            }
+            bytes += copied;
+            len += copied;
+            if !keep_fragments {
+                appended.push((file, len));
+            }
+        }
+
+        // Flushed once for all fragments, which are only recorded and removed afterwards.
+        // A retry starts over from those not removed, and with fsync nothing is removed
+        // before it is on disk in file1.
+        buf1.flush()?;
+        if fsync {
+            buf1.writer.get_ref().sync_all()?;
+        }
+        Ok(())
+    })();
+    if let Err(error) = copied {
+        // Nothing but its length tells what was appended to file1 without a journal,
+        // and the fragments are all still there.
+        if journal.is_none() && !fresh_leader {
+            let (f1, _) = buf1.writer.into_parts();
+            if let Err(truncate_error) = f1.set_len(start) {
+                log::error!(
+                    "Cannot truncate {} back to {} bytes: {}",
+                    files[0].display(),
+                    start,
+                    truncate_error
+                );
+            }
+        }
+        return Err(error);
+    }
+    if let Some(journal) = journal {
+        let records = appended
+            .iter()
+            .map(|&(file, len)| Record {
+                len,
+                leader: files[0].clone(),
+                fragment: Some(file.clone()),
+                id: id.clone(),
+            })
+            .collect::<Vec<_>>();
+        journal.record(&records)?;
+    }
+    for (file, _) in appended {
+        std::fs::remove_file(file)?;
+    }
+    Ok(bytes)
+}
+
+// Fail unless `file` is named with a size of `bytes`, or with no size at all.
+fn check_size(file: &Path, bytes: u64) -> io::Result<()> {
+    match fragment_size(file) {
+        Some(expected) if expected != bytes => Err(ReconstructError::SizeMismatch {
+            path: file.to_path_buf(),
+            expected,
+            actual: bytes,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+// The writer of file1 in `cat_with` and of the work file in `write_at`, flushed at
+// every checkpoint and paced by the throttle, if any.
+struct Checkpointed {
+    writer: io::BufWriter<fs::File>,
+    checkpoint: Option<Checkpoint>,
+    fsync: bool,
+    // Written and elapsed since the last checkpoint.
+    since_bytes: u64,
+    since: Instant,
+    throttle: Option<Throttle>,
+}
+
+impl Write for Checkpointed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let buf = match &self.throttle {
+            // Small enough that the waits between writes stay short.
+            Some(_) => &buf[..buf.len().min(THROTTLE_CHUNK)],
+            None => buf,
+        };
+        let n = self.writer.write(buf)?;
+        if let Some(throttle) = &self.throttle {
+            throttle.take(n as u64);
+        }
+        self.since_bytes += n as u64;
+        let due = match self.checkpoint {
+            Some(Checkpoint::Bytes(bytes)) => self.since_bytes >= bytes,
+            Some(Checkpoint::Every(interval)) => self.since.elapsed() >= interval,
+            None => false,
+        };
+        if due {
+            self.writer.flush()?;
+            if self.fsync {
+                self.writer.get_ref().sync_data()?;
+            }
+            self.since_bytes = 0;
+            self.since = Instant::now();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Copy the rest of `from` to `to` at their positions, within the kernel on Linux,
+// where the filesystem may even share the blocks instead of copying them.
+// Falls back to reading and writing where `copy_file_range` cannot be used,
+// e.g. across filesystems on older kernels.
+#[cfg(target_os = "linux")]
+fn copy_file(from: &fs::File, to: &fs::File) -> io::Result<u64> {
+    use std::os::fd::AsRawFd;
+
+    let mut copied = 0;
+    loop {
+        // Null offsets copy from and to the file positions and advance them.
+        // SAFETY: both descriptors are open for as long as the borrowed files.
+        let n = unsafe {
+            libc::copy_file_range(
+                from.as_raw_fd(),
+                std::ptr::null_mut(),
+                to.as_raw_fd(),
+                std::ptr::null_mut(),
+                1 << 30,
+                0,
+            )
+        };
+        match n {
+            0 => return Ok(copied),
+            n if n > 0 => copied += n as u64,
+            _ => {
+                let error = io::Error::last_os_error();
+                match error.raw_os_error() {
+                    Some(libc::EINTR) => {}
+                    Some(
+                        libc::ENOSYS
+                        | libc::EXDEV
+                        | libc::EINVAL
+                        | libc::EOPNOTSUPP
+                        | libc::EPERM
+                        | libc::EBADF,
+                    ) => {
+                        // Nothing is lost, the positions are where the kernel stopped.
+                        return Ok(copied + io::copy(&mut &*from, &mut &*to)?);
+                    }
+                    _ => return Err(error),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_file(from: &fs::File, to: &fs::File) -> io::Result<u64> {
+    io::copy(&mut &*from, &mut &*to)
+}
+
+// Most bytes written at once to a throttled file.
+const THROTTLE_CHUNK: usize = 64 * 1024;
+// Time the bytes a throttle lets through at once are worth.
+const THROTTLE_BURST: Duration = Duration::from_millis(100);
+
+// Cap on the bytes written per second, shared by every task of a `Reconstructor`.
+// A token bucket holding at most THROTTLE_BURST worth of bytes, so that an idle
+// moment is not made up for by a burst.
+#[derive(Debug, Clone)]
+struct Throttle {
+    bytes_per_sec: f64,
+    // Bytes that may be written right away, negative while writers are waiting,
+    // as of the given instant.
+    bucket: Arc<Mutex<(f64, Instant)>>,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64) -> Throttle {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Throttle {
+            bytes_per_sec,
+            bucket: Arc::new(Mutex::new((
+                bytes_per_sec * THROTTLE_BURST.as_secs_f64(),
+                Instant::now(),
+            ))),
+        }
+    }
+
+    // Account for `bytes` written, waiting until the bucket has room for them.
+    // Writers waiting at the same time queue up, each for the bytes before its own.
+    fn take(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+            let (tokens, since) = &mut *bucket;
+            let now = Instant::now();
+            let capacity = self.bytes_per_sec * THROTTLE_BURST.as_secs_f64();
+            *tokens = (*tokens + now.duration_since(*since).as_secs_f64() * self.bytes_per_sec)
+                .min(capacity)
+                - bytes as f64;
+            *since = now;
+            (*tokens < 0.0).then(|| Duration::from_secs_f64(-*tokens / self.bytes_per_sec))
+        };
+        if let Some(wait) = wait {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+// `file`, opened from `path`, mapped read-only into memory, for `CatOptions::mmap`.
+// None if it is empty or cannot be mapped.
+fn map_file(file: &fs::File, path: &Path) -> Option<memmap2::Mmap> {
+    if file.metadata().ok()?.len() == 0 {
+        return None;
+    }
+    // SAFETY: nothing here writes to a fragment while it is mapped. Another process
+    // truncating it meanwhile kills this one with SIGBUS, as documented for the option.
+    let mapping = match unsafe { memmap2::Mmap::map(file) } {
+        Ok(mapping) => mapping,
+        Err(error) => {
+            log::debug!(
+                "Cannot map {}: {}. Reading it instead.",
+                path.display(),
+                error
+            );
+            return None;
+        }
+    };
+    // Only a hint.
+    #[cfg(unix)]
+    let _ = mapping.advise(memmap2::Advice::Sequential);
+    Some(mapping)
+}
+
+// Copy `reader` to `writer` like `io::copy`, feeding `crc` with the bytes copied.
+fn copy_crc32<R: Read, W: Write>(
+    mut reader: R,
+    writer: &mut W,
+    crc: &mut crc32fast::Hasher,
+) -> io::Result<u64> {
+    let mut buf = [0; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        };
+        crc.update(&buf[..n]);
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+}
+
+// Feed `crc` with the content of `file`.
+fn hash_file(file: &Path, buffer_size: usize, crc: &mut crc32fast::Hasher) -> io::Result<()> {
+    let reader = io::BufReader::with_capacity(buffer_size, fs::File::open(file)?);
+    copy_crc32(reader, &mut io::sink(), crc)?;
+    Ok(())
+}
+
+// Fail unless `file` is named with a CRC32 of `crc`, or with no CRC at all.
+fn check_crc32(file: &Path, crc: &crc32fast::Hasher) -> io::Result<()> {
+    let actual = crc.clone().finalize();
+    match fragment_crc32(file) {
+        Some(expected) if expected != actual => Err(ReconstructError::ChecksumMismatch {
+            path: file.to_path_buf(),
+            algorithm: "CRC32",
+            expected: format!("{:08x}", expected),
+            actual: format!("{:08x}", actual),
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug)]
+struct Task {
+    files: Vec<PathBuf>,
+    // Receives the result once the task has finished, with the CRC32 of what
+    // the task wrote if it was computed.
+    // Disconnected without a message if the task panicked.
+    done: mpsc::Receiver<io::Result<Option<crc32fast::Hasher>>>,
+}
+
+impl Task {
+    // A task that has already finished.
+    fn new() -> Task {
+        let (sender, done) = mpsc::channel();
+        sender.send(Ok(None)).unwrap();
+        Task {
+            files: Vec::new(),
+            done,
+        }
+    }
+
+    // Run `job` on `pool` as this task.
+    fn run<F>(&mut self, pool: &WorkerPool, job: F)
+    where
+        F: FnOnce() -> io::Result<Option<crc32fast::Hasher>> + Send + 'static,
+    {
+        let (sender, done) = mpsc::channel();
+        self.done = done;
+        pool.execute(move || {
+            let _ = sender.send(job());
+        });
+    }
+
+    fn wait(&self) -> io::Result<Option<crc32fast::Hasher>> {
+        self.done
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::other("a task panicked")))
+    }
+}
+
+// When the work on one file is given up, shared by all of its tasks.
+#[derive(Clone)]
+struct Stop {
+    // Set for every file at once, e.g. on a signal.
+    flag: Arc<AtomicBool>,
+    timeout: Option<Duration>,
+    // When the first concatenation of the file started, so that time spent
+    // queued behind other files does not count.
+    started: Arc<OnceLock<Instant>>,
+}
+
+impl Stop {
+    fn new(flag: &Arc<AtomicBool>, timeout: Option<Duration>) -> Stop {
+        Stop {
+            flag: Arc::clone(flag),
+            timeout,
+            started: Arc::new(OnceLock::new()),
+        }
+    }
+
+    // Error if the flag is set or the file has run out of time.
+    // The first call starts the clock.
+    fn check(&self) -> io::Result<()> {
+        if self.flag.load(Ordering::Relaxed) {
+            return Err(stopped());
+        }
+        if self.remaining() == Some(Duration::ZERO) {
+            let timeout = self.timeout.unwrap_or_default();
+            return Err(ReconstructError::TimedOut { timeout }.into());
+        }
+        Ok(())
+    }
+
+    fn remaining(&self) -> Option<Duration> {
+        let timeout = self.timeout?;
+        let started = self.started.get_or_init(Instant::now);
+        Some(timeout.saturating_sub(started.elapsed()))
+    }
+}
+
+// Run `f` until it succeeds, waiting between attempts as `config` says,
+// or until `stop` says to give up. `what` names the file being worked on in the log.
+fn with_retries<T>(
+    what: &str,
+    config: &Config,
+    stop: &Stop,
+    mut f: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let Config {
+        retry_interval,
+        max_retry_interval,
+        max_retries,
+        ..
+    } = *config;
+    let mut retries = 0;
+    loop {
+        stop.check()?;
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(error) if is_permanent(&error) => {
+                log::error!("Error: {}. Not retrying. {}", error, what);
+                return Err(error);
+            }
+            Err(error) if max_retries != 0 && retries >= max_retries => {
+                log::error!(
+                    "Error: {}. Giving up after {} retries. {}",
+                    error,
+                    retries,
+                    what
+                );
+                return Err(error);
+            }
+            Err(error) => {
+                let delay = backoff(retries, retry_interval, max_retry_interval);
+                retries += 1;
+                log::debug!("Error: {}. Retrying in {:?}. {}", error, delay, what);
+                // Wake up in time to give up.
+                std::thread::sleep(stop.remaining().map_or(delay, |r| delay.min(r)));
+            }
+        }
+    }
+}
+
+// `append` `files` with `options`, retrying as `config` says until `stop` says to give up.
+// Fragments appended in place are recorded in `journal`.
+// Returns the bytes appended, and the CRC32 of file1 if `hash` is set.
+fn cat_with_retry(
+    files: &[PathBuf],
+    options: CatOptions,
+    hash: bool,
+    config: &Config,
+    stop: &Stop,
+    journal: Option<&Journal>,
+    throttle: Option<&Throttle>,
+) -> io::Result<(u64, Option<crc32fast::Hasher>)> {
+    let what = format!("Leader = {}", files[0].display());
+    with_retries(&what, config, stop, || {
+        // Start over on every attempt, file1 is hashed again.
+        let mut crc = hash.then(crc32fast::Hasher::new);
+        let bytes = append(files, options, crc.as_mut(), journal, throttle)?;
+        Ok((bytes, crc))
+    })
+}
+
+// Wait before retry number `retries` (counting from 0).
+// Exponential from `base` up to `cap`, the second half of which is random so
+// that tasks failing at the same time do not retry in lockstep.
+fn backoff(retries: usize, base: Duration, cap: Duration) -> Duration {
+    let exponent = retries.min(31) as u32;
+    let delay = base.saturating_mul(1 << exponent).min(cap);
+    // A fresh RandomState is randomly seeded.
+    let random = RandomState::new().build_hasher().finish();
+    let jitter = (delay / 2).mul_f64(random as f64 / u64::MAX as f64);
+    delay / 2 + jitter
+}
+
+/// A reconstructed file.
+#[derive(Debug, Clone)]
+pub struct Reconstructed {
+    /// Where the file was written.
+    pub path: PathBuf,
+    /// Number of fragments it was made of.
+    pub fragments: usize,
+    /// Size of the file.
+    pub bytes: u64,
+    /// Bytes copied by all tasks together, counting each level of the tree.
+    pub bytes_copied: u64,
+    /// CRC32 of the file, if computed while concatenating.
+    pub crc32: Option<u32>,
+}
+
+/// Concatenate `fragments` in order into `output`.
+///
+/// Fragments are consumed unless `config.keep_fragments` is set.
+pub fn reconstruct(
+    output: &Path,
+    fragments: &[PathBuf],
+    config: &Config,
+) -> Result<Reconstructed, ReconstructError> {
+    Reconstructor::new(config.clone()).reconstruct(output, fragments)
+}
+
+/// Number of files appended so far, over all reconstructions.
+#[derive(Debug)]
+pub struct Progress {
+    total: AtomicUsize,
+    done: AtomicUsize,
+    started: Instant,
+}
+
+impl Progress {
+    fn new() -> Progress {
+        Progress {
+            total: AtomicUsize::new(0),
+            done: AtomicUsize::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    /// Files to append for the reconstructions started so far.
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Files appended so far.
+    pub fn done(&self) -> usize {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    // Count the files appended by a successful cat().
+    fn add_done(&self, files: &[PathBuf]) {
+        let appended = files
+            .iter()
+            .skip(1)
+            .filter(|f| !f.as_os_str().is_empty())
+            .count();
+        self.done.fetch_add(appended, Ordering::Relaxed);
+    }
+
+    /// Time since the first reconstruction could start.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// Reconstructs any number of files, sharing `config.jobs` workers among them.
+pub struct Reconstructor {
+    config: Config,
+    pool: WorkerPool,
+    progress: Arc<Progress>,
+    stop: Arc<AtomicBool>,
+    throttle: Option<Throttle>,
+}
+
+impl Reconstructor {
+    pub fn new(config: Config) -> Reconstructor {
+        let pool = if config.sequential {
+            WorkerPool::inline()
+        } else {
+            WorkerPool::new(config.jobs)
+        };
+        let progress = Arc::new(Progress::new());
+        Reconstructor {
+            throttle: config.max_bandwidth.map(Throttle::new),
+            config,
+            pool,
+            progress,
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    /// Once set, no further concatenation is started. Those running finish,
+    /// and every reconstruction not done by then fails as interrupted.
+    pub fn stop_flag(&self) -> &Arc<AtomicBool> {
+        &self.stop
+    }
+
+    /// Concatenate `fragments` in order into `output`.
+    /// May be called from several threads at once.
+    pub fn reconstruct(
+        &self,
+        output: &Path,
+        fragments: &[PathBuf],
+    ) -> Result<Reconstructed, ReconstructError> {
+        // Read before the first fragment is appended to.
+        let mtime = match (self.config.preserve_mtime, fragments.first()) {
+            (Some(source), Some(first)) => original_mtime(output, first, source)?,
+            _ => None,
+        };
+        let reconstructed = reconstruct_on(
+            output,
+            fragments,
+            &self.config,
+            &self.pool,
+            &self.progress,
+            &Stop::new(&self.stop, self.config.target_timeout),
+            self.throttle.as_ref(),
+        )?;
+        // Not for an output skipped as it exists.
+        if let (Some(mtime), true) = (mtime, reconstructed.fragments > 0) {
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&reconstructed.path)?
+                .set_modified(mtime)?;
+        }
+        Ok(reconstructed)
+    }
+}
+
+fn reconstruct_on(
+    output: &Path,
+    fragments: &[PathBuf],
+    config: &Config,
+    pool: &WorkerPool,
+    progress: &Arc<Progress>,
+    stop: &Stop,
+    throttle: Option<&Throttle>,
+) -> io::Result<Reconstructed> {
+    let file = output.display();
+    let num_cat_once = if config.adaptive_fanout {
+        fanout_for(fragments.len())
+    } else {
+        config.num_cat_once
+    };
+    let leaf_fanout = config.leaf_fanout.unwrap_or(num_cat_once);
+    let section_fanout = config.section_fanout.unwrap_or(num_cat_once);
+    let keep_fragments = config.keep_fragments;
+    let decompress = config.decompress;
+    let atomic = config.atomic;
+    let fsync = config.fsync;
+    let buffer_size = config.buffer_size;
+    let verify_sizes = config.verify_sizes;
+    let checkpoint = config.checkpoint;
+    // Decoded fragments cannot be appended to in place.
+    let fresh_leader = keep_fragments || atomic || decompress != Decompress::None;
+
+    // A fragment may have disappeared since the scan.
+    if fragments.is_empty() {
+        log::warn!("No fragments to reconstruct {}. Skipping.", file);
+        return Ok(Reconstructed {
+            path: output.to_path_buf(),
+            fragments: 0,
+            bytes: 0,
+            bytes_copied: 0,
+            crc32: None,
+        });
+    }
+    assert!(leaf_fanout > 1 && section_fanout > 1);
+    // Not check(), which would start the clock while still queued.
+    if stop.flag.load(Ordering::Relaxed) {
+        return Err(stopped());
+    }
+
+    log::info!(target: PER_TARGET_LOG, "Start reconstructing {}", file);
+    let destination = destination(output, config)?;
+    // With `dir_pattern`, the directory is not in the way.
+    let holder = holds_fragments(&destination, fragments, config)?;
+    if !holder && fs::symlink_metadata(&destination).is_ok() {
+        match config.existing {
+            Existing::Error => {
+                return Err(ReconstructError::AlreadyExists { path: destination }.into());
+            }
+            // Only the directory holding the fragments is replaced.
+            Existing::Overwrite if fs::symlink_metadata(&destination)?.is_dir() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} is a directory and not replaced by overwriting",
+                        destination.display()
+                    ),
+                ));
+            }
+            Existing::Overwrite => {
+                log::info!("Overwriting {}", destination.display());
+            }
+            Existing::Skip => {
+                log::info!("Skipping {}. It already exists.", destination.display());
+                return Ok(Reconstructed {
+                    bytes: fs::metadata(&destination)?.len(),
+                    path: destination,
+                    fragments: 0,
+                    bytes_copied: 0,
+                    crc32: None,
+                });
+            }
+        }
+    }
+
+    // Fragments an interrupted run appended in place are in a leader by now.
+    // Any other way would take the leaders for fragments as they were.
+    let journal = journal_of(output, config)?;
+    let consumed = if fresh_leader {
+        if !journal.read()?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is left by an interrupted run appending in place. \
+                    Finish it without copying or assembling elsewhere first.",
+                    journal.path().display()
+                ),
+            ));
+        }
+        std::collections::HashSet::new()
+    } else {
+        journal.recover()?
+    };
+    let fragments = fragments
+        .iter()
+        .filter(|f| !consumed.contains(*f))
+        .cloned()
+        .collect::<Vec<_>>();
+    let fragments = &fragments[..];
+    // Only in-place appends can leave a leader half done.
+    let in_place = (!fresh_leader).then_some(journal.clone());
+
+    if config.parallel_write {
+        journal.remove()?;
+        return reconstruct_at_offsets(output, fragments, config, pool, progress, stop, throttle);
+    }
+
+    // Every file but the final leader is appended once somewhere in the tree.
+    // New leaders get their first fragment appended as well.
+    let num_leaves = fragments.len().div_ceil(leaf_fanout);
+    let num_appends = if fresh_leader {
+        fragments.len() + num_leaves - 1
+    } else {
+        fragments.len() - 1
+    };
+    progress.total.fetch_add(num_appends, Ordering::Relaxed);
+
+    let num_fragments = fragments.len();
+    let bytes_copied = Arc::new(AtomicU64::new(0));
+
+    // Do leaf tasks. The last one gets the remaining fragments, at least one.
+    // A target of at most leaf_fanout fragments is a single leaf, cat at once
+    // and renamed without any section task.
+    log::debug!("{} leaf tasks for {}", num_leaves, file);
+    let mut leaf_tasks: Vec<Task> = Vec::new();
+    for chunk in fragments.chunks(leaf_fanout) {
+        let mut task = Task::new();
+        let mut files = chunk.to_vec();
+        if fresh_leader {
+            // Assemble into a new file so that no fragment is modified.
+            // e.g. vsi_traverse_-s--l-0.txt.00000.tmp
+            let suffix = format!(".{:05}{}", leaf_tasks.len(), config.tmp_suffix);
+            let work = sidecar(&destination, &suffix);
+            files.insert(0, work);
+        }
+        task.files = files.to_vec();
+        let progress = Arc::clone(progress);
+        let bytes_copied = Arc::clone(&bytes_copied);
+        let stop = stop.clone();
+        let config = config.clone();
+        let journal = in_place.clone();
+        let throttle = throttle.cloned();
+        task.run(pool, move || {
+            let options = CatOptions {
+                keep_fragments: keep_fragments || atomic,
+                decompress,
+                fsync,
+                buffer_size,
+                verify_sizes,
+                checkpoint,
+                mmap: config.mmap,
+            };
+            let (bytes, crc) = cat_with_retry(
+                &files,
+                options,
+                config.crc32,
+                &config,
+                &stop,
+                journal.as_ref(),
+                throttle.as_ref(),
+            )?;
+            bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+            progress.add_done(&files);
+            Ok(crc)
+        });
+        leaf_tasks.push(task);
+    }
+
+    // Do section tasks, merging the leaders of up to section_fanout tasks,
+    // until one leader is left.
+    while leaf_tasks.len() > 1 {
+        let mut temp_tasks: Vec<Task> = Vec::new();
+
+        for children in leaf_tasks.chunks(section_fanout) {
+            // Wait here rather than in the job so that no worker is blocked on another.
+            // The CRC32 of the merged leader follows from those of the children.
+            let mut crc: Option<crc32fast::Hasher> = None;
+            for child in children {
+                match (crc.as_mut(), child.wait()?) {
+                    (Some(crc), Some(child_crc)) => crc.combine(&child_crc),
+                    (None, child_crc) => crc = child_crc,
+                    (Some(_), None) => unreachable!("CRC32 computed for some tasks only"),
+                }
+            }
+            let mut task = Task::new();
+            task.files = children.iter().map(|t| t.files[0].clone()).collect();
+            let files = task.files.to_vec();
+            let progress = Arc::clone(progress);
+            let bytes_copied = Arc::clone(&bytes_copied);
+            let stop = stop.clone();
+            let config = config.clone();
+            let journal = in_place.clone();
+            let throttle = throttle.cloned();
+            task.run(pool, move || {
+                let options = CatOptions {
+                    fsync,
+                    buffer_size,
+                    checkpoint,
+                    mmap: config.mmap,
+                    ..CatOptions::default()
+                };
+                let (bytes, _) = cat_with_retry(
+                    &files,
+                    options,
+                    false,
+                    &config,
+                    &stop,
+                    journal.as_ref(),
+                    throttle.as_ref(),
+                )?;
+                bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+                progress.add_done(&files);
+                Ok(crc)
+            });
+            temp_tasks.push(task);
+        }
+
+        leaf_tasks = temp_tasks;
+    }
+
+    let last_task = leaf_tasks
+        .pop()
+        .ok_or_else(|| io::Error::other(format!("No task was run for {}", file)))?;
+    assert_eq!(leaf_tasks.len(), 0);
+
+    // Make sure last task has been finished.
+    let crc = last_task.wait()?.map(crc32fast::Hasher::finalize);
+
+    // Rename vsi_traverse_-s--l-0.txt.FRAG-00000
+    // e.g. rename vsi_traverse_-s--l-0.txt.FRAG-00000 to vsi_traverse_-s--l-0.txt
+    let long_filename = last_task.files[0].clone();
+    if atomic {
+        // Nothing is at the final name until the output is known to be good.
+        if config.verify {
+            verify_checksum(&long_filename, &sidecar(output, ".sha256"))?;
+        }
+        if let Some(crc) = crc {
+            verify_crc32(output, crc, &sidecar(output, ".crc32"))?;
+        }
+        let consumed = if keep_fragments { &[][..] } else { fragments };
+        put_in_place(&long_filename, &destination, holder, consumed, config)?;
+    } else {
+        put_in_place(&long_filename, &destination, holder, &[], config)?;
+        if config.verify {
+            verify_checksum(&destination, &sidecar(output, ".sha256"))?;
+        }
+        if let Some(crc) = crc {
+            verify_crc32(output, crc, &sidecar(output, ".crc32"))?;
+        }
+    }
+
+    journal.remove()?;
+    log::info!(target: PER_TARGET_LOG, "End reconstruction of {}", file);
+    Ok(Reconstructed {
+        bytes: fs::metadata(&destination)?.len(),
+        path: destination,
+        fragments: num_fragments,
+        bytes_copied: bytes_copied.load(Ordering::Relaxed),
+        crc32: crc,
+    })
+}
+
+// Whether `destination` is the directory `fragments` are in, as with `dir_pattern`,
+// so that it is to be replaced by the output. Fails if anything else is in it,
+// or if the fragments are to be kept, which would leave the directory in the way.
+fn holds_fragments(destination: &Path, fragments: &[PathBuf], config: &Config) -> io::Result<bool> {
+    if !fs::symlink_metadata(destination).is_ok_and(|m| m.is_dir())
+        || !fragments.iter().all(|f| f.parent() == Some(destination))
+    {
+        return Ok(false);
+    }
+    let in_the_way = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} cannot replace its directory: {}",
+                destination.display(),
+                reason
+            ),
+        )
+    };
+    if config.keep_fragments {
+        return Err(in_the_way(String::from("the fragments are to be kept")));
+    }
+    let fragments = fragments.iter().collect::<std::collections::HashSet<_>>();
+    for entry in fs::read_dir(destination)? {
+        let path = entry?.path();
+        if !fragments.contains(&path) {
+            return Err(in_the_way(format!("{} is not a fragment", path.display())));
+        }
+    }
+    Ok(true)
+}
+
+// Move the finished `from` to `destination` and remove the `consumed` fragments.
+// If `destination` is the `holder` of the fragments, as found by `holds_fragments`,
+// `from` is moved out of it first and the directory is removed once empty.
+fn put_in_place(
+    from: &Path,
+    destination: &Path,
+    holder: bool,
+    consumed: &[PathBuf],
+    config: &Config,
+) -> io::Result<()> {
+    if holder {
+        // e.g. vsi_traverse_-s--l-0.txt.tmp
+        let staged = sidecar(destination, &config.tmp_suffix);
+        if from != staged {
+            rename(from, &staged)?;
+        }
+        for fragment in consumed {
+            fs::remove_file(fragment)?;
+        }
+        fs::remove_dir(destination)?;
+        rename(&staged, destination)?;
+        if config.fsync {
+            sync_parent(destination)?;
+        }
+        return Ok(());
+    }
+    rename(from, destination)?;
+    if config.fsync {
+        sync_parent(destination)?;
+    }
+    for fragment in consumed {
+        fs::remove_file(fragment)?;
+    }
+    Ok(())
+}
+
+// Write every fragment into a preallocated work file at its offset, all at the
+// same time, then rename the work file to `destination`.
+// Fragments are only removed once the output is complete.
+fn reconstruct_at_offsets(
+    output: &Path,
+    fragments: &[PathBuf],
+    config: &Config,
+    pool: &WorkerPool,
+    progress: &Arc<Progress>,
+    stop: &Stop,
+    throttle: Option<&Throttle>,
+) -> io::Result<Reconstructed> {
+    let destination = destination_path(output, config)?;
+    let holder = holds_fragments(&destination, fragments, config)?;
+    let sizes = fragments
+        .iter()
+        .map(
+            |fragment| match (fragment_size(fragment), config.decompress) {
+                (Some(size), _) => Ok(size),
+                (None, Decompress::None) => Ok(fs::metadata(fragment)?.len()),
+                (None, _) => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{}: decompressed size unknown without a {} suffix",
+                        fragment.display(),
+                        SIZE_MARKER
+                    ),
+                )),
+            },
+        )
+        .collect::<io::Result<Vec<_>>>()?;
+    let total = sizes.iter().sum();
+
+    // e.g. vsi_traverse_-s--l-0.txt.tmp
+    let work = sidecar(&destination, &config.tmp_suffix);
+    fs::File::create(&work)?.set_len(total)?;
+    progress.total.fetch_add(fragments.len(), Ordering::Relaxed);
+
+    let mut tasks: Vec<Task> = Vec::new();
+    let mut offset = 0;
+    for (fragment, size) in fragments.iter().zip(sizes) {
+        let mut task = Task::new();
+        task.files = vec![fragment.clone()];
+        let work = work.clone();
+        let fragment = fragment.clone();
+        let progress = Arc::clone(progress);
+        let config = config.clone();
+        let stop = stop.clone();
+        let throttle = throttle.cloned();
+        task.run(pool, move || {
+            let what = format!("Fragment = {}", fragment.display());
+            let crc = with_retries(&what, &config, &stop, || {
+                write_at(&work, offset, &fragment, size, &config, throttle.as_ref())
+            })?;
+            progress.done.fetch_add(1, Ordering::Relaxed);
+            Ok(crc)
+        });
+        tasks.push(task);
+        offset += size;
+    }
+    // Wait for every task, so that none writes to the work file any more.
+    let results = tasks.iter().map(Task::wait).collect::<Vec<_>>();
+    let crcs = results.into_iter().collect::<io::Result<Vec<_>>>()?;
+    // The fragments are in order, so their CRC32s combine into that of the output.
+    let crc = crcs
+        .into_iter()
+        .flatten()
+        .reduce(|mut crc, fragment_crc| {
+            crc.combine(&fragment_crc);
+            crc
+        })
+        .map(crc32fast::Hasher::finalize);
+
+    if config.fsync {
+        fs::OpenOptions::new().write(true).open(&work)?.sync_all()?;
+    }
+    if config.verify {
+        verify_checksum(&work, &sidecar(output, ".sha256"))?;
+    }
+    if let Some(crc) = crc {
+        verify_crc32(output, crc, &sidecar(output, ".crc32"))?;
+    }
+    let consumed = if config.keep_fragments {
+        &[][..]
+    } else {
+        fragments
+    };
+    put_in_place(&work, &destination, holder, consumed, config)?;
+
+    log::info!(target: PER_TARGET_LOG, "End reconstruction of {}", output.display());
+    Ok(Reconstructed {
+        path: destination,
+        fragments: fragments.len(),
+        bytes: total,
+        bytes_copied: total,
+        crc32: crc,
+    })
+}
+
+// Copy `fragment` into `work` at `offset`, failing unless it holds exactly `size` bytes.
+// Returns the CRC32 of the fragment if `config.crc32` is set.
+fn write_at(
+    work: &Path,
+    offset: u64,
+    fragment: &Path,
+    size: u64,
+    config: &Config,
+    throttle: Option<&Throttle>,
+) -> io::Result<Option<crc32fast::Hasher>> {
+    let mut file = fs::OpenOptions::new().write(true).open(work)?;
+    file.seek(io::SeekFrom::Start(offset))?;
+    let mut writer = Checkpointed {
+        writer: io::BufWriter::with_capacity(config.buffer_size, file),
+        checkpoint: None,
+        fsync: false,
+        since_bytes: 0,
+        since: Instant::now(),
+        throttle: throttle.cloned(),
+    };
+    let fragment_file = fs::File::open(fragment).map_err(|error| open_error(fragment, error))?;
+    let reader = io::BufReader::with_capacity(config.buffer_size, fragment_file);
+    // Never write into the range of the next fragment.
+    let mut reader = config.decompress.decoder(reader)?.take(size);
+    let mut crc = config.crc32.then(crc32fast::Hasher::new);
+    let copied = match crc.as_mut() {
+        Some(crc) => copy_crc32(&mut reader, &mut writer, crc)?,
+        None => io::copy(&mut reader, &mut writer)?,
+    };
+    writer.flush()?;
+    let extra = io::copy(&mut reader.into_inner(), &mut io::sink())?;
+    if copied != size || extra != 0 {
+        return Err(ReconstructError::SizeMismatch {
+            path: fragment.to_path_buf(),
+            expected: size,
+            actual: copied + extra,
+        }
+        .into());
+    }
+    if let Some(crc) = &crc {
+        check_crc32(fragment, crc)?;
+    }
+    Ok(crc)
+}
+
+// Modification time `output` is to be given according to `source`, if any.
+fn original_mtime(
+    output: &Path,
+    first: &Path,
+    source: MtimeSource,
+) -> io::Result<Option<SystemTime>> {
+    match source {
+        MtimeSource::FirstFragment => Ok(Some(
+            fs::metadata(first)
+                .map_err(|error| open_error(first, error))?
+                .modified()?,
+        )),
+        MtimeSource::Sidecar => {
+            let sidecar = sidecar(output, MTIME_SUFFIX);
+            let content = match fs::read_to_string(&sidecar) {
+                Ok(content) => content,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                    log::warn!(
+                        "No {} found. {} keeps the time it is written.",
+                        sidecar.display(),
+                        output.display()
+                    );
+                    return Ok(None);
+                }
+                Err(error) => return Err(error),
+            };
+            let word = content.split_whitespace().next().unwrap_or("");
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: invalid time {:?}", sidecar.display(), word),
+                )
+            };
+            let secs = word.parse::<f64>().map_err(|_| invalid())?;
+            let since_epoch = Duration::try_from_secs_f64(secs.abs()).map_err(|_| invalid())?;
+            let mtime = if secs < 0.0 {
+                UNIX_EPOCH.checked_sub(since_epoch)
+            } else {
+                UNIX_EPOCH.checked_add(since_epoch)
+            };
+            mtime.map(Some).ok_or_else(invalid)
+        }
+    }
+}
+
+// Error opening `leader` to append to in place, naming it and the kind of error.
+fn leader_error(leader: &Path, error: io::Error) -> io::Error {
+    if error.kind() == io::ErrorKind::NotFound {
+        return ReconstructError::MissingLeader {
+            path: leader.to_path_buf(),
+        }
+        .into();
+    }
+    io::Error::new(
+        error.kind(),
+        format!(
+            "cannot open leader {} for appending: {:?}: {}",
+            leader.display(),
+            error.kind(),
+            error
+        ),
+    )
+}
+
+// Whether retrying after `error` is pointless, as nothing is going to bring back
+// what is missing.
+fn is_permanent(error: &io::Error) -> bool {
+    matches!(
+        error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<ReconstructError>()),
+        Some(ReconstructError::MissingLeader { .. })
+    )
+}
+
+// Error of work not started because the reconstructor was stopped.
+fn stopped() -> io::Error {
+    ReconstructError::Stopped.into()
+}
+
+// Error opening `path`, a missing fragment if it is not found.
+fn open_error(path: &Path, error: io::Error) -> io::Error {
+    if error.kind() == io::ErrorKind::NotFound {
+        return ReconstructError::MissingFragment {
+            path: path.to_path_buf(),
+        }
+        .into();
+    }
+    io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+}
+
+/// Write `fragments` in order to `writer`, decoded as `config.decompress` says.
+///
+/// The fragments are left as they are. A missing fragment is an error,
+/// as is one named with a size it does not have if `config.verify_sizes` is set.
+/// Returns the number of bytes written.
+pub fn cat_to<W: Write>(
+    fragments: &[PathBuf],
+    writer: &mut W,
+    config: &Config,
+) -> Result<u64, ReconstructError> {
+    let mut bytes = 0;
+    for fragment in fragments {
+        let file = fs::File::open(fragment).map_err(|error| open_error(fragment, error))?;
+        let reader = io::BufReader::with_capacity(config.buffer_size, file);
+        let copied = io::copy(&mut config.decompress.decoder(reader)?, writer)?;
+        if config.verify_sizes {
+            check_size(fragment, copied)?;
+        }
+        bytes += copied;
+    }
+    writer.flush()?;
+    Ok(bytes)
+}
+
+/// Path the reconstructed `output` is written to.
+/// The output directory is created if needed.
+pub fn destination(output: &Path, config: &Config) -> io::Result<PathBuf> {
+    let destination = destination_path(output, config)?;
+    if config.output_dir.is_some() {
+        if let Some(dir) = destination.parent() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    Ok(destination)
+}
+
+/// Journal of the fragments of `output` appended in place.
+pub fn journal_of(output: &Path, config: &Config) -> io::Result<Journal> {
+    let destination = destination_path(output, config)?;
+    Ok(Journal::new(
+        sidecar(&destination, JOURNAL_SUFFIX),
+        config.fsync,
+    ))
+}
+
+/// Path the reconstructed `output` is written to, without touching the file system.
+pub fn destination_path(output: &Path, config: &Config) -> io::Result<PathBuf> {
+    let Some(output_dir) = &config.output_dir else {
+        return Ok(output.to_path_buf());
+    };
+    if !config.flatten {
+        // e.g. DIR/sub/a.txt -> OUTPUT_DIR/sub/a.txt
+        let output = normalize_path(output);
+        let root_dir = normalize_path(&config.root_dir);
+        let relative = output.strip_prefix(&root_dir).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is not below {}",
+                    output.display(),
+                    config.root_dir.display()
+                ),
+            )
+        })?;
+        return Ok(output_dir.join(relative));
+    }
+    let basename = output.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no file name", output.display()),
+        )
+    })?;
+    Ok(output_dir.join(basename))
+}
+
+/// Rename `from` to `to`, copying the data when they are on different filesystems.
+///
+/// An existing file at `to` is replaced on every platform, also on Windows
+/// where `fs::rename` moves with `MOVEFILE_REPLACE_EXISTING`. Whether `to` may
+/// be replaced at all is decided by `Config::existing` before any fragment is touched.
+pub fn rename(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(error) if error.kind() == io::ErrorKind::CrossesDevices => {
+            log::debug!(
+                "Cannot rename {} across filesystems. Copying instead.",
+                from.display()
+            );
+            fs::copy(from, to).map_err(|source| ReconstructError::CrossDeviceRename {
+                from: from.to_path_buf(),
+                to: to.to_path_buf(),
+                source,
+            })?;
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+// Sync the directory containing `path`, so that a rename to it survives a crash.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    // Directories cannot be opened for syncing everywhere.
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// `path` with `extension` appended.
+/// e.g. vsi_traverse_-s--l-0.txt -> vsi_traverse_-s--l-0.txt.sha256
+fn sidecar(path: &Path, extension: &str) -> PathBuf {
+    let mut sidecar = OsString::from(path);
+    sidecar.push(extension);
+    PathBuf::from(sidecar)
+}
+
+/// SHA-256 of the content of `path` as lowercase hex.
+pub fn sha256_hex(path: &Path) -> io::Result<String> {
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Check `output` against the digest stored in `sidecar`.
+///
+/// The sidecar may be in `sha256sum` format, only the first word is used.
+/// A missing sidecar is not an error.
+pub fn verify_checksum(output: &Path, sidecar: &Path) -> io::Result<()> {
+    let expected = match fs::read_to_string(sidecar) {
+        Ok(content) => content
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_lowercase(),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            log::debug!("No {} found. Skip verification.", sidecar.display());
+            return Ok(());
+        }
+        Err(error) => return Err(error),
+    };
+
+    let actual = sha256_hex(output)?;
+    if actual != expected {
+        return Err(ReconstructError::ChecksumMismatch {
+            path: output.to_path_buf(),
+            algorithm: "SHA-256",
+            expected,
+            actual,
+        }
+        .into());
+    }
+    log::debug!("Checksum of {} verified", output.display());
+    Ok(())
+}
+
+/// Check the CRC32 `actual` computed for `output` against the one stored in `sidecar`,
+/// as 8 hex digits.
+///
+/// Only the first word of the sidecar is used. A missing sidecar is not an error.
+pub fn verify_crc32(output: &Path, actual: u32, sidecar: &Path) -> io::Result<()> {
+    let content = match fs::read_to_string(sidecar) {
+        Ok(content) => content,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            log::debug!("No {} found. Skip CRC32 verification.", sidecar.display());
+            return Ok(());
+        }
+        Err(error) => return Err(error),
+    };
+    let word = content.split_whitespace().next().unwrap_or_default();
+    let expected = u32::from_str_radix(word, 16).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: invalid CRC32 {:?}: {}", sidecar.display(), word, e),
+        )
+    })?;
+    if actual != expected {
+        return Err(ReconstructError::ChecksumMismatch {
+            path: output.to_path_buf(),
+            algorithm: "CRC32",
+            expected: format!("{:08x}", expected),
+            actual: format!("{:08x}", actual),
+        }
+        .into());
+    }
+    log::debug!("CRC32 of {} verified", output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fragment `index` of `output` with the default delimiter.
+    fn fragment_name(output: &Path, index: usize) -> PathBuf {
+        let mut name = output.as_os_str().to_owned();
+        name.push(format!("{}{:05}", DELIMITER_DEFAULT, index));
+        PathBuf::from(name)
+    }
+
+    // Write `count` fragments of `output` of different sizes, the first one empty.
+    // Returns their names in order and the content they make together.
+    fn write_fragments(output: &Path, count: usize) -> (Vec<PathBuf>, Vec<u8>) {
+        let mut original = Vec::new();
+        let fragments = (0..count)
+            .map(|i| {
+                let fragment = (0..i * 7).map(|j| (i * 31 + j) as u8).collect::<Vec<_>>();
+                fs::write(fragment_name(output, i), &fragment).unwrap();
+                original.extend(fragment);
+                fragment_name(output, i)
+            })
+            .collect();
+        (fragments, original)
+    }
+
+    #[test]
+    fn reconstruct_around_the_fanout() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            num_cat_once: 4,
+            ..Config::default()
+        };
+        // One task, one full task, and trees of two and three levels.
+        for count in [3, 4, 5, 9] {
+            let output = dir.path().join(format!("{}.bin", count));
+            let (fragments, original) = write_fragments(&output, count);
+            let reconstructed = reconstruct(&output, &fragments, &config).unwrap();
+            assert_eq!(reconstructed.fragments, count);
+            assert_eq!(reconstructed.bytes, original.len() as u64);
+            assert_eq!(
+                fs::read(&reconstructed.path).unwrap(),
+                original,
+                "{}",
+                count
+            );
+            assert!(fragments.iter().all(|f| !f.exists()));
+        }
+    }
+
+    #[test]
+    fn cat_with_mmap_writes_the_same_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (fragments, original) = write_fragments(&dir.path().join("a.bin"), 5);
+        for (name, mmap) in [("read.bin", false), ("mapped.bin", true)] {
+            let output = dir.path().join(name);
+            let files = [vec![output.clone()], fragments.clone()].concat();
+            let options = CatOptions {
+                keep_fragments: true,
+                mmap,
+                ..CatOptions::default()
+            };
+            assert_eq!(cat_with(&files, options).unwrap(), original.len() as u64);
+            assert_eq!(fs::read(&output).unwrap(), original, "mmap: {}", mmap);
+        }
+    }
+
+    #[test]
+    fn reconstruct_zstd_fragments() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("a.bin");
+        let chunks: [&[u8]; 3] = [b"first chunk, ", b"", b"and the last one"];
+        let fragments = chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut fragment = fragment_name(&output, i).into_os_string();
+                fragment.push(Decompress::Zstd.extension());
+                fs::write(&fragment, zstd::encode_all(*chunk, 0).unwrap()).unwrap();
+                PathBuf::from(fragment)
+            })
+            .collect::<Vec<_>>();
+        let config = Config {
+            decompress: Decompress::Zstd,
+            ..Config::default()
+        };
+        let reconstructed = reconstruct(&output, &fragments, &config).unwrap();
+        assert_eq!(fs::read(reconstructed.path).unwrap(), chunks.concat());
+    }
+
+    #[test]
+    fn fragment_pattern_matches_the_delimiter_literally() {
+        let re = fragment_pattern(DELIMITER_DEFAULT, "").unwrap();
+        assert!(!re.is_match(b"XFRAG-0"));
+        assert!(!re.is_match(b"a.txtXFRAG-00000"));
+        assert!(!re.is_match(b".FRAG-00000"));
+        let captures = re.captures(b"a.txt.FRAG-00000").unwrap();
+        assert_eq!(&captures["target"], b"a.txt");
+        assert_eq!(&captures["index"], b"00000");
+    }
+
+    #[test]
+    fn group_fragments_ignores_directories_named_like_fragments() {
+        let re = fragment_pattern(DELIMITER_DEFAULT, "").unwrap();
+        let paths = [
+            "weird.FRAG-dir/a.txt.FRAG-00001",
+            "weird.FRAG-dir/a.txt.FRAG-00000",
+            "weird.FRAG-dir/notes.txt",
+        ]
+        .map(PathBuf::from);
+        let map = group_fragments(paths, &re);
+        assert_eq!(map.len(), 1);
+        assert_eq!(
+            map[Path::new("weird.FRAG-dir/a.txt")],
+            [
+                "weird.FRAG-dir/a.txt.FRAG-00000",
+                "weird.FRAG-dir/a.txt.FRAG-00001"
+            ]
+            .map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn group_fragments_orders_by_number() {
+        let re = fragment_pattern(DELIMITER_DEFAULT, "").unwrap();
+        let paths = ["a.FRAG-10", "a.FRAG-2", "a.FRAG-1"].map(PathBuf::from);
+        let map = group_fragments(paths, &re);
+        assert_eq!(
+            map[Path::new("a")],
+            ["a.FRAG-1", "a.FRAG-2", "a.FRAG-10"].map(PathBuf::from)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn group_fragments_keeps_names_that_are_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let re = fragment_pattern(DELIMITER_DEFAULT, "").unwrap();
+        let target = OsStr::from_bytes(b"caf\xe9.txt");
+        assert_eq!(path_from_bytes(b"caf\xe9.txt").unwrap(), Path::new(target));
+
+        let paths = [&b"caf\xe9.txt.FRAG-00001"[..], b"caf\xe9.txt.FRAG-00000"]
+            .map(|name| PathBuf::from(OsStr::from_bytes(name)));
+        let map = group_fragments(paths, &re);
+        assert_eq!(map.len(), 1);
+        assert_eq!(
+            map[Path::new(target)],
+            [&b"caf\xe9.txt.FRAG-00000"[..], b"caf\xe9.txt.FRAG-00001"]
+                .map(|name| PathBuf::from(OsStr::from_bytes(name)))
+        );
+    }
+
+    #[test]
+    fn fanout_for_rounds_the_root_up() {
+        assert_eq!(fanout_for(0), 2);
+        assert_eq!(fanout_for(1), 2);
+        assert_eq!(fanout_for(2), 2);
+        assert_eq!(fanout_for(4), 2);
+        assert_eq!(fanout_for(5), 3);
+        assert_eq!(fanout_for(9), 3);
+        assert_eq!(fanout_for(10), 4);
+        assert_eq!(fanout_for(1_000_000), 1000);
+        assert_eq!(fanout_for(1_000_001), 1001);
+        assert_eq!(fanout_for(usize::MAX), 1 << (usize::BITS / 2));
+    }
+
+    #[test]
+    fn overwrite_refuses_a_directory_that_does_not_hold_the_fragments() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("a.bin");
+        fs::create_dir(&output).unwrap();
+        fs::write(output.join("notes.txt"), b"kept").unwrap();
+        let (fragments, _) = write_fragments(&output, 3);
+        let config = Config {
+            existing: Existing::Overwrite,
+            ..Config::default()
+        };
+        let error = reconstruct(&output, &fragments, &config).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(fs::read(output.join("notes.txt")).unwrap(), b"kept");
+        assert!(fragments.iter().all(|f| f.exists()));
+    }
+
+    #[test]
+    fn fragment_pattern_reads_the_total_before_the_markers() {
+        let re = fragment_pattern(DELIMITER_DEFAULT, "").unwrap();
+        for name in [
+            "a.txt.FRAG-00003-of-00100",
+            "a.txt.FRAG-00003-of-00100.sz1024",
+            "a.txt.FRAG-00003-of-00100.crc1a2b3c4d",
+            "a.txt.FRAG-00003-of-00100.sz1024.crc1a2b3c4d",
+        ] {
+            let fragment = Path::new(name);
+            assert_eq!(fragment_index(&re, fragment), Some(3), "{}", name);
+            assert_eq!(fragment_total(&re, fragment), Some(100), "{}", name);
+            let captures = re.captures(name.as_bytes()).unwrap();
+            assert_eq!(&captures["target"], b"a.txt");
+        }
+        let fragment = Path::new("a.txt.FRAG-00003-of-00100.sz1024.crc1a2b3c4d");
+        assert_eq!(fragment_size(fragment), Some(1024));
+        assert_eq!(fragment_crc32(fragment), Some(0x1a2b3c4d));
+        // Without a total the markers are still where they were.
+        let fragment = Path::new("a.txt.FRAG-00003.sz1024");
+        assert_eq!(fragment_index(&re, fragment), Some(3));
+        assert_eq!(fragment_total(&re, fragment), None);
+    }
+
+    #[test]
+    fn dir_pattern_takes_only_numbered_names() {
+        let re = dir_pattern("").unwrap();
+        for (name, index) in [
+            ("a.txt/00003", Some(3)),
+            ("a.txt/FRAG-00003", Some(3)),
+            ("a.txt/FRAG-00003.sz1024", Some(3)),
+            ("a.txt/2024-notes.txt", None),
+            ("a.txt/notes2", None),
+            ("a.txt/FRAG-", None),
+        ] {
+            assert_eq!(re.is_match(name.as_bytes()), index.is_some(), "{}", name);
+            assert_eq!(fragment_index(&re, Path::new(name)), index, "{}", name);
+        }
+    }
+
+    #[test]
+    fn failed_append_leaves_the_leader_as_it_was() {
+        let dir = tempfile::tempdir().unwrap();
+        let leader = dir.path().join("a.bin");
+        fs::write(&leader, b"leader").unwrap();
+        let good = dir.path().join("a.bin.FRAG-00001");
+        fs::write(&good, b"appended").unwrap();
+        let short = dir.path().join("a.bin.FRAG-00002.sz100");
+        fs::write(&short, b"short").unwrap();
+
+        let options = CatOptions {
+            verify_sizes: true,
+            ..CatOptions::default()
+        };
+        let files = [leader.clone(), good.clone(), short.clone()];
+        assert!(cat_with(&files, options).is_err());
+        assert_eq!(fs::read(&leader).unwrap(), b"leader");
+        assert!(good.exists() && short.exists());
+    }
+
+    #[test]
+    fn copying_refuses_the_journal_of_an_interrupted_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("a.bin");
+        let (fragments, _) = write_fragments(&output, 3);
+        let config = Config {
+            keep_fragments: true,
+            ..Config::default()
+        };
+        let journal = journal_of(&output, &config).unwrap();
+        journal
+            .record(&[Record {
+                len: 0,
+                leader: fragments[0].clone(),
+                fragment: None,
+                id: None,
+            }])
+            .unwrap();
+
+        assert!(reconstruct(&output, &fragments, &config).is_err());
+        assert!(!output.exists());
+        assert!(journal.path().exists());
+    }
+
+    #[test]
+    fn reconstruct_combines_the_crc32_of_the_fragments() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("a.bin");
+        let (plain, original) = write_fragments(&output, 6);
+        let fragments = plain
+            .iter()
+            .map(|fragment| {
+                let content = fs::read(fragment).unwrap();
+                let mut named = fragment.as_os_str().to_owned();
+                named.push(format!("{}{:08x}", CRC_MARKER, crc32fast::hash(&content)));
+                fs::rename(fragment, &named).unwrap();
+                PathBuf::from(named)
+            })
+            .collect::<Vec<_>>();
+        let config = Config {
+            crc32: true,
+            num_cat_once: 2,
+            ..Config::default()
+        };
+        let reconstructed = reconstruct(&output, &fragments, &config).unwrap();
+        assert_eq!(reconstructed.crc32, Some(crc32fast::hash(&original)));
+        assert_eq!(fs::read(&output).unwrap(), original);
+    }
+
+    #[test]
+    fn reconstruct_fails_for_a_fragment_with_another_crc32() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("a.bin");
+        let (mut fragments, _) = write_fragments(&output, 3);
+        let mut named = fragments[2].as_os_str().to_owned();
+        named.push(format!("{}00000000", CRC_MARKER));
+        fs::rename(&fragments[2], &named).unwrap();
+        fragments[2] = PathBuf::from(named);
+        let config = Config {
+            crc32: true,
+            max_retries: 1,
+            retry_interval: Duration::from_millis(1),
+            ..Config::default()
+        };
+        assert!(reconstruct(&output, &fragments, &config).is_err());
+        assert!(!output.exists());
+        assert!(fragments.iter().all(|f| f.exists()));
+        // What was appended to the first fragment is cut off by the next run.
+        assert!(journal_of(&output, &config).unwrap().path().exists());
+    }
+
+    #[test]
+    fn throttle_waits_for_the_bytes_past_the_burst() {
+        let throttle = Throttle::new(10_000);
+        let started = Instant::now();
+        // The burst, a tenth of a second of bytes.
+        throttle.take(1000);
+        assert!(started.elapsed() < Duration::from_millis(50));
+        throttle.take(500);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(45), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    }
+}
@@ -1,307 +1,2640 @@
 extern crate env_logger;
 extern crate getopts;
+extern crate globset;
 extern crate log;
+extern crate regex;
 
-use once_cell::sync::Lazy;
-use regex::Regex;
+mod report;
+mod selftest;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use mtreconstruct::{
+    cat_to, declared_totals, destination_path, dir_pattern, duplicate_fragments, fragment_pattern,
+    fragment_size, group_fragments, journal_of, manifest, missing_indices, normalize_path,
+    path_from_bytes, suffix_pattern, Checkpoint, Config, Decompress, Existing, MtimeSource,
+    ReconstructError, Reconstructed, Reconstructor, ScanOptions, VisitDir, DELIMITER_DEFAULT,
+    NUM_CAT_ONCE_DEFAULT, PER_TARGET_LOG,
+};
+use regex::bytes::Regex;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::fs::{self, DirEntry};
-use std::io;
-use std::io::Read;
-use std::io::Write;
-use std::path::Path;
-use std::sync::Mutex;
-
-const NUM_CAT_ONCE_DEFATLT: usize = 32;
-static NUM_CAT_ONCE: Lazy<Mutex<usize>> = Lazy::new(|| Mutex::new(NUM_CAT_ONCE_DEFATLT));
-
-struct VisitDir {
-    root: Box<dyn Iterator<Item = io::Result<DirEntry>>>,
-    children: Box<dyn Iterator<Item = VisitDir>>,
-}
-
-impl VisitDir {
-    fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-        let root = Box::new(fs::read_dir(&path)?);
-        let children = Box::new(fs::read_dir(&path)?.filter_map(|e| {
-            let e = e.ok()?;
-            if e.file_type().ok()?.is_dir() {
-                return VisitDir::new(e.path()).ok();
-            }
-            None
-        }));
-        Ok(VisitDir { root, children })
-    }
+use std::ffi::OsString;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-    fn entries(self) -> Box<dyn Iterator<Item = io::Result<DirEntry>>> {
-        Box::new(self.root.chain(self.children.flat_map(|s| s.entries())))
-    }
+use report::{Outcome, SkipReason, Skipped};
+
+// Locked in DIR while reconstructing, see --no-lock.
+const LOCK_FILE: &str = ".mtreconstruct.lock";
+
+// How often --progress reports.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Accepted by --log.
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+// Parsed command line.
+struct Args {
+    config: Config,
+    root_dir: PathBuf,
+    dry_run: bool,
+    list_targets: bool,
+    delimiter: String,
+    fragment_regex: Option<String>,
+    layout: Layout,
+    allow_gaps: bool,
+    reverse_order: bool,
+    min_fragments: usize,
+    // --after, with the time as given.
+    after: Option<(SystemTime, String)>,
+    order: Order,
+    log_format: LogFormat,
+    scan: ScanOptions,
+    progress: bool,
+    json: bool,
+    metrics_file: Option<PathBuf>,
+    to_stdout: bool,
+    manifest: Option<PathBuf>,
+    stdin: bool,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    resume: Option<PathBuf>,
+    name_transform: Option<(Regex, String)>,
+    only: Option<PathBuf>,
+    // --fragment-range, positions of the fragments kept, the end excluded.
+    fragment_range: Option<std::ops::Range<usize>>,
+    self_test: bool,
+    lock: bool,
 }
 
-impl Iterator for VisitDir {
-    type Item = io::Result<DirEntry>;
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(item) = self.root.next() {
-            return Some(item);
+// What is used unless given on the command line.
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            config: Config::default(),
+            root_dir: PathBuf::from("."),
+            dry_run: false,
+            list_targets: false,
+            delimiter: String::from(DELIMITER_DEFAULT),
+            fragment_regex: None,
+            layout: Layout::Suffix,
+            allow_gaps: false,
+            reverse_order: false,
+            min_fragments: 1,
+            after: None,
+            order: Order::Name,
+            log_format: LogFormat::Default,
+            scan: ScanOptions::default(),
+            progress: false,
+            json: false,
+            metrics_file: None,
+            to_stdout: false,
+            manifest: None,
+            stdin: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            resume: None,
+            name_transform: None,
+            only: None,
+            fragment_range: None,
+            self_test: false,
+            lock: true,
         }
-        if let Some(child) = self.children.next() {
-            self.root = child.entries();
-            return self.next();
-        }
-        None
     }
 }
 
-fn set_loglevel(loglevel: &str) {
-    std::env::set_var("RUST_LOG", loglevel);
+// How fragments are told apart from other files and grouped by target.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    // e.g. a.txt.FRAG-00000, see --delimiter and --fragment-regex.
+    Suffix,
+    // e.g. a.txt/FRAG-00000, every numbered file in a directory named after the target.
+    Dir,
+}
+
+// How finished targets are logged, see --log-format.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    // A line as every target starts and one as it ends.
+    Default,
+    // One key=value line per target as it finishes.
+    Oneline,
+}
+
+// Order targets are listed and started in.
+#[derive(Clone, Copy)]
+enum Order {
+    // By name.
+    Name,
+    // Largest output first, then by name. Targets of unknown size come last.
+    Size,
+}
+
+// Log to stderr at `level`, or the level in RUST_LOG if not given,
+// and to `log_file` if given.
+// If `quiet`, only warnings, errors and the final report are logged whatever the level.
+// With `LogFormat::Oneline`, the lines marking the start and the end of every target are not.
+// Levels are colored if stderr is a terminal and there is no `log_file`, unless
+// `no_color` or NO_COLOR is set, see https://no-color.org, whatever RUST_LOG_STYLE says.
+fn init_logger(
+    level: Option<log::LevelFilter>,
+    log_file: Option<&Path>,
+    quiet: bool,
+    no_color: bool,
+    log_format: LogFormat,
+) -> std::io::Result<()> {
+    let mut builder = env_logger::Builder::from_default_env();
+    if no_color || env::var_os("NO_COLOR").is_some() {
+        builder.write_style(env_logger::WriteStyle::Never);
+    }
+    if let Some(level) = level {
+        builder.filter_level(level);
+    }
+    if quiet {
+        builder.filter_level(log::LevelFilter::Warn);
+        builder.filter_module("mtreconstruct::report", log::LevelFilter::Info);
+    }
+    if log_format == LogFormat::Oneline {
+        builder.filter_module(PER_TARGET_LOG, log::LevelFilter::Warn);
+    }
+    if let Some(log_file) = log_file {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_file)?;
+        builder.target(env_logger::Target::Pipe(Box::new(Tee(file))));
+    }
+    builder.init();
+    Ok(())
+}
+
+// Writes everything to stderr and to the file.
+struct Tee(std::fs::File);
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write_all(buf)?;
+        std::io::stderr().write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()?;
+        std::io::stderr().flush()
+    }
 }
 
 fn print_usage(program: &str, opts: &getopts::Options) {
     let brief = format!(
         "Multithread reconstruction.
-Usage: {program}
-       {program} -n [NUMBER]"
+Usage: {program} [DIR]
+       {program} -n [NUMBER] [DIR]
+       {program} split --chunk-size SIZE FILE...
+Exit status is 0 on success, 1 on errors before any reconstruction and 2 if some files failed."
     );
     print!("{}", opts.usage(&brief));
     std::process::exit(0);
 }
 
-fn parse_args() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
+fn print_version() {
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    std::process::exit(0);
+}
+
+// The arguments of the program for getopts, which only takes UTF-8. Every other
+// argument, or its value after --option= or -o, is replaced by a placeholder
+// that `path` turns back into the original, so that any path can be given.
+struct CommandLine {
+    args: Vec<String>,
+    originals: Vec<OsString>,
+}
+
+// Starts a placeholder, which no argument can contain, followed by its index in
+// `CommandLine::originals`.
+const PLACEHOLDER: char = '\0';
+
+impl CommandLine {
+    fn new() -> CommandLine {
+        let mut command_line = CommandLine {
+            args: Vec::new(),
+            originals: Vec::new(),
+        };
+        for arg in env::args_os() {
+            let arg = match arg.into_string() {
+                Ok(arg) => arg,
+                Err(arg) => command_line.placeholder(arg),
+            };
+            command_line.args.push(arg);
+        }
+        command_line
+    }
+
+    // Placeholder for `arg`, which is not UTF-8, keeping an option in front of its value.
+    fn placeholder(&mut self, arg: OsString) -> String {
+        let bytes = arg.as_encoded_bytes();
+        let value_start = if bytes.starts_with(b"--") {
+            bytes.iter().position(|&b| b == b'=').map_or(0, |i| i + 1)
+        } else if bytes.starts_with(b"-") && bytes.len() > 2 {
+            2
+        } else {
+            0
+        };
+        let split = std::str::from_utf8(&bytes[..value_start])
+            .ok()
+            .zip(path_from_bytes(&bytes[value_start..]));
+        let (option, value) = match split {
+            Some((option, value)) => (option.to_string(), value.into_os_string()),
+            None => (String::new(), arg),
+        };
+        self.originals.push(value);
+        format!("{}{}{}", option, PLACEHOLDER, self.originals.len() - 1)
+    }
+
+    // The path given as `arg`, the original argument if it is a placeholder.
+    fn path(&self, arg: String) -> PathBuf {
+        let original = arg
+            .strip_prefix(PLACEHOLDER)
+            .and_then(|index| index.parse::<usize>().ok())
+            .and_then(|index| self.originals.get(index));
+        match original {
+            Some(original) => PathBuf::from(original),
+            None => PathBuf::from(arg),
+        }
+    }
+}
+
+fn parse_args() -> Result<Args, Box<dyn std::error::Error>> {
+    let command_line = CommandLine::new();
+    let args = &command_line.args;
     let program = args[0].clone();
     let mut opts = getopts::Options::new();
 
-    opts.optopt("n", "number", "number", "");
+    // A larger NUMBER makes the reduction tree shallower, so every byte is copied
+    // fewer times, but each task reads that many files one after the other and
+    // keeps fewer workers busy. Fast disks with many cores can go well above 100.
+    opts.optopt(
+        "n",
+        "number",
+        &format!(
+            "Files concatenated by one task, at least 2. Default is {}.\n\
+            Larger values copy the data fewer times but run fewer tasks in parallel.",
+            NUM_CAT_ONCE_DEFAULT
+        ),
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        "leaf-fanout",
+        "Fragments concatenated by one leaf task, at least 2. Default is NUMBER.",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "section-fanout",
+        "Leaders merged by one section task, at least 2. Default is NUMBER.",
+        "COUNT",
+    );
+    opts.optopt(
+        "j",
+        "jobs",
+        "Number of concatenations running at the same time over all files. Default is the number of CPUs.",
+        "JOBS",
+    );
+    opts.optflag(
+        "",
+        "adaptive-fanout",
+        "Pick NUMBER for each file from its number of fragments N, the square root of N rounded \
+        up, so that small files are not spread over many tasks and large ones not over few. \
+        An explicit --number wins.",
+    );
+    opts.optflag(
+        "",
+        "force-sequential",
+        "Reconstruct one target after the other and concatenate on a single thread, for debugging.\n\
+        Ignores --jobs.",
+    );
     opts.optflag("h", "help", "Print this message.");
-    opts.optopt("", "log", "debug, info, warn, error", "");
-
-    if args.iter().any(|e| e == "--test") {
-        //test_code();
-        unreachable!();
-    }
+    opts.optflag("V", "version", "Print the version.");
+    opts.optflag(
+        "",
+        "test",
+        "Split files of random sizes into fragments in a temporary directory, reconstruct them \
+        with the other options given and check the result. DIR is ignored.",
+    );
+    opts.optopt("", "log", &LOG_LEVELS.join(", "), "LEVEL");
+    opts.optflag(
+        "q",
+        "quiet",
+        "Only log warnings, errors and the final summary, whatever --log says.",
+    );
+    opts.optflag(
+        "",
+        "no-color",
+        "Do not color log levels, as when NO_COLOR is set. They are only colored on a terminal.",
+    );
+    opts.optopt(
+        "",
+        "log-format",
+        "default logs a line as every target starts and ends, oneline one line per target as it \
+        finishes, e.g. target=a.txt fragments=3 bytes=1024 elapsed_ms=5 status=ok",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "log-file",
+        "Write the log to FILE as well as to stderr.",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "max-retries",
+        "Retries of a failed concatenation before giving up. 0 retries forever. Default is 10.",
+        "RETRIES",
+    );
+    opts.optopt(
+        "",
+        "retry-interval",
+        "Seconds to wait before retrying a failed concatenation, doubling on every retry. Default is 5.",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "max-retry-interval",
+        "Longest wait in seconds between retries. Default is 60.",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "target-timeout",
+        "Give up a file not reconstructed within SECS of starting on it. \
+        The other files still finish.",
+        "SECS",
+    );
+    opts.optflag(
+        "",
+        "dry-run",
+        "Print the reconstruction plan and the size of each output without touching any files.\n\
+        Fails if an output directory has not enough free space.",
+    );
+    opts.optflag(
+        "",
+        "no-lock",
+        &format!(
+            "Do not lock {} in DIR, which keeps another run over the same tree from starting.",
+            LOCK_FILE
+        ),
+    );
+    opts.optflag(
+        "",
+        "list-targets",
+        "Print every file that would be reconstructed and its number of fragments, then exit.",
+    );
+    opts.optflag(
+        "",
+        "progress",
+        "Report the number of files appended and the estimated time left.",
+    );
+    opts.optflag(
+        "",
+        "json",
+        "Print a JSON summary of every target to stdout when done.",
+    );
+    opts.optopt(
+        "",
+        "metrics-file",
+        "Write Prometheus text format metrics of the run to FILE when done.",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "to-stdout",
+        "Write the single target found to stdout instead of reconstructing it, one fragment \
+        after the other. The fragments are left as they are. Select the target with --include.",
+    );
+    opts.optflag(
+        "",
+        "keep-fragments",
+        "Leave the fragments on disk after reconstruction.",
+    );
+    opts.optflag(
+        "",
+        "copy-instead-of-append",
+        "Copy every fragment, the first one included, into a new file and never modify \
+        or remove any, e.g. for a read-only fragment store. Same as --keep-fragments.",
+    );
+    opts.optflag(
+        "",
+        "atomic",
+        "Assemble into temporary files and remove the fragments only once the output is complete.",
+    );
+    opts.optopt(
+        "",
+        "buffer-size",
+        "Read and write buffer of each concatenation, e.g. 4M. K, M and G are powers of 1024. Default is 8K.",
+        "SIZE",
+    );
+    opts.optflag(
+        "",
+        "overwrite",
+        "Replace outputs that already exist. By default such targets fail.",
+    );
+    opts.optopt(
+        "",
+        "tmp-suffix",
+        "Suffix of the files assembled before being renamed to the output, e.g. .partial. Default is .tmp.",
+        "SUFFIX",
+    );
+    opts.optopt(
+        "",
+        "checkpoint-interval",
+        "Flush what has been appended so far every SIZE bytes, e.g. 1G, or every SECS seconds \
+        when followed by s, e.g. 30s. With --fsync the data is synced to disk as well.",
+        "SIZE|SECSs",
+    );
+    opts.optflag(
+        "",
+        "skip",
+        "Leave targets whose output already exists alone.",
+    );
+    opts.optflag(
+        "",
+        "verify-sizes",
+        "Check fragments named like <file>.FRAG-00000.sz<BYTES> hold exactly BYTES bytes.",
+    );
+    opts.optflag(
+        "",
+        "mmap",
+        "Read uncompressed fragments by mapping them into memory.\n\
+        A fragment truncated by another process meanwhile kills mtreconstruct.",
+    );
+    opts.optopt(
+        "",
+        "max-bandwidth",
+        "Write at most MB megabytes (10^6 bytes) per second over all files at once, e.g. 50 or 2.5.",
+        "MB",
+    );
+    opts.optopt(
+        "",
+        "preserve-mtime",
+        "Give each output the modification time in <output>.mtime, in seconds since the Unix \
+        epoch, with sidecar, or that of its first fragment with first-fragment.",
+        "SOURCE",
+    );
+    opts.optflag(
+        "",
+        "parallel-write",
+        "Write all fragments of a file at their offsets at the same time instead of concatenating them.\n\
+        Compressed fragments need a size suffix, see --verify-sizes.",
+    );
+    opts.optflag(
+        "",
+        "fsync",
+        "Sync the reconstructed data to disk before removing any fragment.",
+    );
+    opts.optflag(
+        "",
+        "verify",
+        "Check each output against <output>.sha256 if it exists.",
+    );
+    opts.optflag(
+        "",
+        "crc32",
+        "Compute the CRC32 of each output while concatenating and check it against <output>.crc32 \
+        if it exists. Fragments named like <file>.FRAG-00000.crc<HEX> are checked as well.",
+    );
+    opts.optopt(
+        "",
+        "output-dir",
+        "Write the reconstructed files to this directory.",
+        "DIR",
+    );
+    opts.optflag(
+        "",
+        "flatten",
+        "Write every file straight into the output directory by its file name. The default.",
+    );
+    opts.optflag(
+        "",
+        "no-flatten",
+        "Keep the subdirectories of DIR below the output directory.",
+    );
+    opts.optflag(
+        "",
+        "allow-gaps",
+        "Reconstruct even if some fragment numbers are missing.",
+    );
+    opts.optflag(
+        "",
+        "reverse-order",
+        "Concatenate the fragments of each target from the last to the first, \
+        for splitters numbering the tail of the file 0.",
+    );
+    opts.optopt(
+        "",
+        "order",
+        "Order targets are started in: name, or size for the largest output first. Default is name.",
+        "ORDER",
+    );
+    opts.optopt(
+        "",
+        "min-fragments",
+        "Skip targets with fewer fragments, e.g. 2 to leave unsplit files alone. Default is 1.",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "after",
+        "Only reconstruct fragments modified after TIME, in seconds since the Unix epoch\n\
+        or RFC 3339, e.g. 2024-05-01T12:00:00Z. Targets with older fragments as well are warned about.",
+        "TIME",
+    );
+    opts.optflag(
+        "",
+        "follow-symlinks",
+        "Descend into symbolic links to directories.",
+    );
+    opts.optflag(
+        "",
+        "include-hidden",
+        "Also look at files and into directories whose name starts with a dot, e.g. .git.",
+    );
+    opts.optflag(
+        "",
+        "non-recursive",
+        "Only look for fragments directly in DIR. Same as --max-depth 0",
+    );
+    opts.optopt(
+        "",
+        "max-depth",
+        "Levels of subdirectories of DIR to look into. Default is no limit.",
+        "DEPTH",
+    );
+    opts.optopt(
+        "",
+        "delimiter",
+        "String separating the file name from the fragment number. Default is .FRAG-",
+        "DELIMITER",
+    );
+    opts.optopt(
+        "",
+        "fragment-regex",
+        "Suffix identifying fragments instead of --delimiter. The group named index orders the \
+        fragments, a group named total gives their number. e.g. _chunk_(?P<index>\\d+)",
+        "REGEX",
+    );
+    opts.optopt(
+        "",
+        "layout",
+        "How fragments are laid out: suffix, e.g. a.txt.FRAG-00000, or dir, e.g. a.txt/FRAG-00000\n\
+        with every file in the directory numbered a fragment. Default is suffix.",
+        "LAYOUT",
+    );
+    opts.optopt(
+        "",
+        "decompress",
+        "Decompress the fragments while concatenating. FORMAT is gzip or zstd",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "manifest",
+        "Reconstruct the fragments listed in this file instead of scanning DIR.\n\
+        One <output>\\t<fragment>[\\t<size>] per line.",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "stdin",
+        "Read the fragment paths from stdin, one per line, instead of scanning DIR.",
+    );
+    opts.optmulti(
+        "",
+        "include",
+        "Only reconstruct fragments whose path relative to DIR matches GLOB. Repeatable.",
+        "GLOB",
+    );
+    opts.optmulti(
+        "",
+        "exclude",
+        "Skip fragments whose path relative to DIR matches GLOB. Repeatable. Wins over --include.",
+        "GLOB",
+    );
+    opts.optopt(
+        "",
+        "resume",
+        "Record completed targets in FILE and skip those already recorded.",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "only",
+        "Only reconstruct the target named NAME, its output path or the end of it, e.g. sub/a.txt.",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "fragment-range",
+        "Only concatenate the fragments at positions START to END, END excluded, counting from 0 \
+        in the order they are concatenated, e.g. 0..10 or 100.., into <output>.range-START-END. \
+        Fragments are kept, for narrowing down where an output goes wrong.",
+        "START..END",
+    );
+    opts.optopt(
+        "",
+        "name-transform",
+        "Replace the first match of REGEX in each output path with REPLACEMENT, which may refer to groups as $1.",
+        "REGEX=REPLACEMENT",
+    );
 
     let matches = opts.parse(&args[1..])?;
+    let mut parsed = Args::default();
 
     if matches.opt_present("h") {
         print_usage(&program, &opts);
         unreachable!();
     }
 
+    if matches.opt_present("V") {
+        print_version();
+        unreachable!();
+    }
+
+    if matches.opt_present("test") {
+        parsed.self_test = true;
+    }
+
+    // Set up logging first so that the rest of the parsing can log.
+    let mut loglevel = None;
     if matches.opt_present("log") {
-        let loglevel = matches.opt_str("log").unwrap_or_else(|| "info".to_string());
-        set_loglevel(&loglevel);
+        let level_arg = matches.opt_str("log").unwrap_or_else(|| "info".to_string());
+        if !LOG_LEVELS.contains(&level_arg.as_str()) {
+            let level_error = std::io::Error::other(format!(
+                "Unknown log level: {}. Use one of {}",
+                level_arg,
+                LOG_LEVELS.join(", ")
+            ));
+            return Err(Box::new(level_error));
+        }
+        loglevel = Some(level_arg.parse()?);
     }
+    parsed.log_format = match matches.opt_str("log-format").as_deref() {
+        None | Some("default") => LogFormat::Default,
+        Some("oneline") => LogFormat::Oneline,
+        Some(format) => {
+            let format_error = std::io::Error::other(format!(
+                "Unknown log format: {}. Use default or oneline",
+                format
+            ));
+            return Err(Box::new(format_error));
+        }
+    };
+    init_logger(
+        loglevel,
+        matches
+            .opt_str("log-file")
+            .map(|file| command_line.path(file))
+            .as_deref(),
+        matches.opt_present("quiet"),
+        matches.opt_present("no-color"),
+        parsed.log_format,
+    )?;
 
     if matches.opt_present("number") {
         let number_arg = matches
             .opt_str("number")
-            .unwrap_or(format!("{}", NUM_CAT_ONCE_DEFATLT));
-        let number: usize = number_arg.parse()?;
-        if !(2..=100).contains(&number) {
-            let number_error = std::io::Error::new(std::io::ErrorKind::Other, "Input number error");
-            return Err(Box::new(number_error));
+            .unwrap_or(format!("{}", NUM_CAT_ONCE_DEFAULT));
+        parsed.config.num_cat_once = parse_fanout(&number_arg, "--number")?;
+    }
+    if let Some(fanout_arg) = matches.opt_str("leaf-fanout") {
+        parsed.config.leaf_fanout = Some(parse_fanout(&fanout_arg, "--leaf-fanout")?);
+    }
+    if let Some(fanout_arg) = matches.opt_str("section-fanout") {
+        parsed.config.section_fanout = Some(parse_fanout(&fanout_arg, "--section-fanout")?);
+    }
+    if matches.opt_present("adaptive-fanout") {
+        if matches.opt_present("number") {
+            log::info!("--number overrides --adaptive-fanout");
+        } else {
+            parsed.config.adaptive_fanout = true;
         }
-        assert!(number > 1);
-        *NUM_CAT_ONCE.lock()? = number;
     }
 
-    Ok(())
-}
+    if let Some(jobs_arg) = matches.opt_str("jobs") {
+        parsed.config.jobs = parse_count(&jobs_arg, "--jobs", 1)?;
+    }
+    if matches.opt_present("force-sequential") {
+        parsed.config.sequential = true;
+    }
 
-// Append the content of file2 to file1.
-// file1 will be modified.
-// file2.. will be removed.
-fn cat(files: &Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
-    if files.len() <= 1 {
-        return Ok(());
+    if let Some(retries_arg) = matches.opt_str("max-retries") {
+        parsed.config.max_retries = parse_count(&retries_arg, "--max-retries", 0)?;
     }
-    if files.first().unwrap().is_empty() {
-        return Ok(());
+
+    if let Some(interval_arg) = matches.opt_str("retry-interval") {
+        parsed.config.retry_interval = parse_secs(&interval_arg)?;
     }
-    let f1 = std::fs::OpenOptions::new().append(true).open(&files[0])?;
-    let mut buf1 = std::io::BufWriter::new(f1);
 
-    for file in files.iter().skip(1) {
-        if file.is_empty() {
-            continue;
+    if let Some(interval_arg) = matches.opt_str("max-retry-interval") {
+        parsed.config.max_retry_interval = parse_secs(&interval_arg)?;
+    }
+
+    if let Some(timeout_arg) = matches.opt_str("target-timeout") {
+        parsed.config.target_timeout = Some(parse_secs(&timeout_arg)?);
+    }
+
+    if matches.opt_present("dry-run") {
+        parsed.dry_run = true;
+    }
+
+    if matches.opt_present("list-targets") {
+        parsed.list_targets = true;
+    }
+
+    if matches.opt_present("no-lock") {
+        parsed.lock = false;
+    }
+
+    if matches.opt_present("progress") {
+        parsed.progress = true;
+    }
+
+    if matches.opt_present("json") {
+        parsed.json = true;
+    }
+    parsed.metrics_file = matches
+        .opt_str("metrics-file")
+        .map(|file| command_line.path(file));
+
+    if matches.opt_present("to-stdout") {
+        if parsed.json {
+            let stdout_error = std::io::Error::other("--to-stdout and --json conflict");
+            return Err(Box::new(stdout_error));
         }
+        parsed.to_stdout = true;
+    }
 
-        // Skip this file
-        if std::fs::metadata(&file).is_err() {
-            continue;
+    if matches.opt_present("keep-fragments") || matches.opt_present("copy-instead-of-append") {
+        parsed.config.keep_fragments = true;
+    }
+
+    if matches.opt_present("atomic") {
+        parsed.config.atomic = true;
+    }
+
+    if let Some(size_arg) = matches.opt_str("buffer-size") {
+        parsed.config.buffer_size = parse_size(&size_arg)?;
+    }
+
+    if let Some(suffix) = matches.opt_str("tmp-suffix") {
+        // The work file would be the output itself.
+        if suffix.is_empty() {
+            let suffix_error = std::io::Error::other("--tmp-suffix must not be empty");
+            return Err(Box::new(suffix_error));
         }
+        parsed.config.tmp_suffix = suffix;
+    }
 
-        let f2 = std::fs::File::open(&file)?;
-        let mut buf2 = std::io::BufReader::new(f2);
+    if let Some(interval_arg) = matches.opt_str("checkpoint-interval") {
+        let checkpoint = match interval_arg.strip_suffix('s') {
+            Some(secs) => Checkpoint::Every(parse_secs(secs)?),
+            None => Checkpoint::Bytes(parse_size(&interval_arg)? as u64),
+        };
+        parsed.config.checkpoint = Some(checkpoint);
+    }
 
-        let mut b: Vec<u8> = Vec::new();
-        buf2.read_to_end(&mut b)?;
-        buf1.write_all(&b)?;
-        std::fs::remove_file(&file)?;
+    match (
+        matches.opt_present("overwrite"),
+        matches.opt_present("skip"),
+    ) {
+        (true, true) => {
+            let existing_error = std::io::Error::other("--overwrite and --skip conflict");
+            return Err(Box::new(existing_error));
+        }
+        (true, false) => parsed.config.existing = Existing::Overwrite,
+        (false, true) => parsed.config.existing = Existing::Skip,
+        (false, false) => {}
     }
 
-    Ok(())
+    if matches.opt_present("verify-sizes") {
+        parsed.config.verify_sizes = true;
+    }
+
+    if matches.opt_present("mmap") {
+        parsed.config.mmap = true;
+    }
+
+    if let Some(bandwidth_arg) = matches.opt_str("max-bandwidth") {
+        parsed.config.max_bandwidth = Some(parse_bandwidth(&bandwidth_arg)?);
+    }
+
+    if let Some(source) = matches.opt_str("preserve-mtime") {
+        if parsed.to_stdout {
+            let mtime_error = std::io::Error::other("--preserve-mtime and --to-stdout conflict");
+            return Err(Box::new(mtime_error));
+        }
+        parsed.config.preserve_mtime = Some(match source.as_str() {
+            "sidecar" => MtimeSource::Sidecar,
+            "first-fragment" => MtimeSource::FirstFragment,
+            _ => {
+                let source_error = std::io::Error::other(format!(
+                    "Unknown --preserve-mtime source: {}. Use sidecar or first-fragment",
+                    source
+                ));
+                return Err(Box::new(source_error));
+            }
+        });
+    }
+
+    if matches.opt_present("parallel-write") {
+        parsed.config.parallel_write = true;
+    }
+
+    if matches.opt_present("fsync") {
+        parsed.config.fsync = true;
+    }
+
+    if matches.opt_present("verify") {
+        parsed.config.verify = true;
+    }
+
+    if matches.opt_present("crc32") {
+        parsed.config.crc32 = true;
+    }
+
+    if let Some(output_dir) = matches.opt_str("output-dir") {
+        parsed.config.output_dir = Some(command_line.path(output_dir));
+    }
+
+    match (
+        matches.opt_present("flatten"),
+        matches.opt_present("no-flatten"),
+    ) {
+        (true, true) => {
+            let flatten_error = std::io::Error::other("--flatten and --no-flatten conflict");
+            return Err(Box::new(flatten_error));
+        }
+        (_, no_flatten) => parsed.config.flatten = !no_flatten,
+    }
+
+    if matches.opt_present("allow-gaps") {
+        parsed.allow_gaps = true;
+    }
+
+    if matches.opt_present("reverse-order") {
+        parsed.reverse_order = true;
+    }
+
+    if let Some(order) = matches.opt_str("order") {
+        parsed.order = match order.as_str() {
+            "name" => Order::Name,
+            "size" => Order::Size,
+            _ => {
+                let order_error = std::io::Error::other(format!("Unknown order: {}", order));
+                return Err(Box::new(order_error));
+            }
+        };
+    }
+
+    if let Some(min_arg) = matches.opt_str("min-fragments") {
+        parsed.min_fragments = parse_count(&min_arg, "--min-fragments", 0)?;
+    }
+    if let Some(after_arg) = matches.opt_str("after") {
+        parsed.after = Some((parse_time(&after_arg)?, after_arg));
+    }
+
+    if matches.opt_present("follow-symlinks") {
+        parsed.scan.follow_symlinks = true;
+    }
+
+    if matches.opt_present("include-hidden") {
+        parsed.scan.include_hidden = true;
+    }
+
+    if matches.opt_present("non-recursive") {
+        parsed.scan.max_depth = Some(0);
+    }
+
+    if let Some(depth_arg) = matches.opt_str("max-depth") {
+        parsed.scan.max_depth = Some(parse_count(&depth_arg, "--max-depth", 0)?);
+    }
+
+    if let Some(delimiter) = matches.opt_str("delimiter") {
+        if delimiter.is_empty() {
+            let delimiter_error = std::io::Error::other("Delimiter must not be empty");
+            return Err(Box::new(delimiter_error));
+        }
+        parsed.delimiter = delimiter;
+    }
+
+    if let Some(fragment_regex) = matches.opt_str("fragment-regex") {
+        if matches.opt_present("delimiter") {
+            let regex_error = std::io::Error::other("--fragment-regex and --delimiter conflict");
+            return Err(Box::new(regex_error));
+        }
+        parsed.fragment_regex = Some(fragment_regex);
+    }
+
+    if let Some(layout) = matches.opt_str("layout") {
+        parsed.layout = match layout.as_str() {
+            "suffix" => Layout::Suffix,
+            "dir" => Layout::Dir,
+            _ => {
+                let layout_error = std::io::Error::other(format!("Unknown layout: {}", layout));
+                return Err(Box::new(layout_error));
+            }
+        };
+        for option in ["delimiter", "fragment-regex"] {
+            if parsed.layout == Layout::Dir && matches.opt_present(option) {
+                let layout_error =
+                    std::io::Error::other(format!("--layout dir and --{} conflict", option));
+                return Err(Box::new(layout_error));
+            }
+        }
+    }
+
+    if let Some(format) = matches.opt_str("decompress") {
+        parsed.config.decompress = match format.as_str() {
+            "gzip" => Decompress::Gzip,
+            "zstd" => Decompress::Zstd,
+            _ => {
+                let format_error =
+                    std::io::Error::other(format!("Unknown decompression format: {}", format));
+                return Err(Box::new(format_error));
+            }
+        };
+    }
+
+    parsed.manifest = matches
+        .opt_str("manifest")
+        .map(|file| command_line.path(file));
+    parsed.stdin = matches.opt_present("stdin");
+    parsed.include = matches.opt_strs("include");
+    parsed.exclude = matches.opt_strs("exclude");
+    parsed.resume = matches
+        .opt_str("resume")
+        .map(|file| command_line.path(file));
+    parsed.only = matches.opt_str("only").map(|only| command_line.path(only));
+
+    if let Some(range_arg) = matches.opt_str("fragment-range") {
+        parsed.fragment_range = Some(parse_range(&range_arg)?);
+        // The fragments make a partial file, which must not use them up.
+        parsed.config.keep_fragments = true;
+    }
+
+    if let Some(transform) = matches.opt_str("name-transform") {
+        // The replacement is less likely to contain '=' than the pattern.
+        let Some((pattern, replacement)) = transform.rsplit_once('=') else {
+            let transform_error =
+                std::io::Error::other("--name-transform must be REGEX=REPLACEMENT");
+            return Err(Box::new(transform_error));
+        };
+        parsed.name_transform = Some((Regex::new(pattern)?, replacement.to_string()));
+    }
+
+    // Directory to scan for fragments. Defaults to the current directory.
+    if let Some(dir) = matches.free.first() {
+        parsed.root_dir = command_line.path(dir.clone());
+    }
+    parsed.config.root_dir = parsed.root_dir.clone();
+
+    Ok(parsed)
+}
+
+// Files concatenated by one task, given to `option`.
+fn parse_fanout(fanout: &str, option: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    // One file per task would never reduce the number of leaders.
+    parse_count(fanout, option, 2)
+}
+
+// A count of at least `min` given to `option`, naming both when it is not one.
+fn parse_count(count: &str, option: &str, min: usize) -> Result<usize, Box<dyn std::error::Error>> {
+    match count.parse::<usize>() {
+        Ok(count) if count >= min => Ok(count),
+        _ => Err(Box::new(std::io::Error::other(format!(
+            "invalid value '{}' for {}: expected an integer of at least {}",
+            count, option, min
+        )))),
+    }
+}
+
+// Positions START..END, END excluded, either of which may be left out.
+// e.g. 0..10, 100.. -> 100..usize::MAX
+fn parse_range(range: &str) -> Result<std::ops::Range<usize>, Box<dyn std::error::Error>> {
+    let invalid = || {
+        std::io::Error::other(format!(
+            "invalid value '{}' for --fragment-range: expected START..END with START < END",
+            range
+        ))
+    };
+    let (start, end) = range.split_once("..").ok_or_else(invalid)?;
+    let start = match start {
+        "" => 0,
+        start => start.parse().map_err(|_| invalid())?,
+    };
+    let end = match end {
+        "" => usize::MAX,
+        end => end.parse().map_err(|_| invalid())?,
+    };
+    if start >= end {
+        return Err(Box::new(invalid()));
+    }
+    Ok(start..end)
+}
+
+// Positive number of megabytes per second, in bytes per second.
+// e.g. 2.5 -> 2500000
+fn parse_bandwidth(bandwidth: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    match bandwidth.parse::<f64>() {
+        Ok(mb) if mb.is_finite() && mb * 1_000_000.0 >= 1.0 => Ok((mb * 1_000_000.0) as u64),
+        _ => Err(Box::new(std::io::Error::other(format!(
+            "invalid value '{}' for --max-bandwidth: expected a positive number of MB/s",
+            bandwidth
+        )))),
+    }
+}
+
+// Positive number of bytes with an optional K, M or G suffix.
+// e.g. 4M -> 4194304
+fn parse_size(size: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    let (digits, shift) = match size.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&size[..i], 10),
+        Some((i, 'M' | 'm')) => (&size[..i], 20),
+        Some((i, 'G' | 'g')) => (&size[..i], 30),
+        _ => (size, 0),
+    };
+    let bytes = digits
+        .parse::<usize>()?
+        .checked_mul(1 << shift)
+        .filter(|&bytes| bytes > 0)
+        .ok_or_else(|| std::io::Error::other(format!("Invalid size: {}", size)))?;
+    Ok(bytes)
 }
 
-#[derive(Debug)]
-struct Task {
-    files: Vec<String>,
-    handler: std::thread::JoinHandle<()>,
+// Non-negative number of seconds, fractions allowed.
+fn parse_secs(secs: &str) -> Result<std::time::Duration, Box<dyn std::error::Error>> {
+    let secs: f64 = secs.parse()?;
+    Ok(std::time::Duration::try_from_secs_f64(secs)?)
 }
 
-impl Task {
-    fn new() -> Task {
-        let handler = std::thread::spawn(|| {});
-        Task {
-            files: Vec::new(),
-            handler,
+// Seconds since the Unix epoch, or an RFC 3339 timestamp.
+// e.g. 1714564800, 2024-05-01T12:00:00Z, 2024-05-01 14:00:00.5+02:00
+fn parse_time(time: &str) -> Result<SystemTime, Box<dyn std::error::Error>> {
+    if let Ok(secs) = time.parse::<f64>() {
+        return Ok(UNIX_EPOCH + Duration::try_from_secs_f64(secs)?);
+    }
+    let secs = rfc3339_secs(time).ok_or_else(|| {
+        std::io::Error::other(format!(
+            "invalid time '{}': expected seconds since the Unix epoch or RFC 3339, \
+            e.g. 2024-05-01T12:00:00Z",
+            time
+        ))
+    })?;
+    let since_epoch = Duration::try_from_secs_f64(secs.abs())?;
+    let time = if secs < 0.0 {
+        UNIX_EPOCH.checked_sub(since_epoch)
+    } else {
+        UNIX_EPOCH.checked_add(since_epoch)
+    };
+    Ok(time.ok_or_else(|| std::io::Error::other("time out of range"))?)
+}
+
+// Seconds since the Unix epoch of YYYY-MM-DDTHH:MM:SS[.frac](Z|+HH:MM|-HH:MM).
+fn rfc3339_secs(time: &str) -> Option<f64> {
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = time.get(range)?;
+        digits.bytes().all(|b| b.is_ascii_digit()).then_some(())?;
+        digits.parse().ok()
+    };
+    let separators = time.as_bytes();
+    if separators.len() < 20
+        || separators[4] != b'-'
+        || separators[7] != b'-'
+        || !matches!(separators[10], b'T' | b't' | b' ')
+        || separators[13] != b':'
+        || separators[16] != b':'
+    {
+        return None;
+    }
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+    {
+        return None;
+    }
+    // 60 is a leap second.
+    if second > 60 {
+        return None;
+    }
+
+    let mut rest = time.get(19..)?;
+    let mut fraction = 0.0;
+    if let Some(after_point) = rest.strip_prefix('.') {
+        let digits = after_point
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(after_point.len());
+        if digits == 0 {
+            return None;
         }
+        fraction = format!("0.{}", &after_point[..digits]).parse().ok()?;
+        rest = &after_point[digits..];
     }
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes().first() {
+                Some(b'+') => 1,
+                Some(b'-') => -1,
+                _ => return None,
+            };
+            let (hours, minutes) = rest[1..].split_once(':')?;
+            if hours.len() != 2 || minutes.len() != 2 {
+                return None;
+            }
+            let (hours, minutes) = (hours.parse::<i64>().ok()?, minutes.parse::<i64>().ok()?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Some((secs - offset) as f64 + fraction)
 }
 
-fn reconstruct(file: &String, fragments: &[String]) {
-    log::info!("Start reconstructing {}", file);
-    let num_cat_once = *NUM_CAT_ONCE.lock().unwrap();
-    let mut fragments = fragments.to_vec();
-    fragments.reverse();
+// Number of days of `month` (1 to 12) of `year` in the Gregorian calendar.
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days from 1970-01-01 to the given day of the proleptic Gregorian calendar.
+// After Howard Hinnant's days_from_civil.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
 
-    // Do leaf tasks.
-    let mut leaf_tasks: Vec<Task> = Vec::new();
+// Print the progress of `reconstructor` to stderr until `stop` receives.
+// On a terminal the line is redrawn in place.
+fn report_progress(reconstructor: &Reconstructor, stop: mpsc::Receiver<()>) {
+    let terminal = std::io::stderr().is_terminal();
     loop {
-        let mut task = Task::new();
-        for _ in 0..num_cat_once {
-            let f = fragments.pop().unwrap_or_default();
-            task.files.push(f.clone());
-        }
-        let files = task.files.to_vec();
-        if files.first().unwrap().is_empty() {
-            break;
-        }
-        task.handler = std::thread::spawn(move || {
-            loop {
-                match cat(&files) {
-                    Ok(_) => break,
-                    Err(error) => {
-                        log::debug!(
-                            "Error: {}. Retrying in 5 secs. Leader = {}",
-                            error,
-                            files[0]
-                        );
-                        std::thread::sleep(std::time::Duration::from_secs(5));
-                    }
-                }
+        let finished = !matches!(
+            stop.recv_timeout(PROGRESS_INTERVAL),
+            Err(mpsc::RecvTimeoutError::Timeout)
+        );
+
+        let progress = reconstructor.progress();
+        let (done, total) = (progress.done(), progress.total());
+        let elapsed = progress.elapsed().as_secs_f64();
+        let rate = done as f64 / elapsed.max(f64::EPSILON);
+        let percent = 100.0 * done as f64 / total.max(1) as f64;
+        let eta = if rate > 0.0 {
+            format!("{:.0} s", total.saturating_sub(done) as f64 / rate)
+        } else {
+            String::from("unknown")
+        };
+        let line = format!(
+            "{}/{} files appended ({:.1}%), {:.1} files/s, ETA {}",
+            done, total, percent, rate, eta
+        );
+
+        if terminal {
+            eprint!("\r{:<80}", line);
+            if finished {
+                eprintln!();
             }
-            //cat(&files).unwrap();
+        } else {
+            eprintln!("{}", line);
+        }
+        if finished {
+            return;
+        }
+    }
+}
+
+// Message passed to panic!(), if any.
+fn panic_message(payload: &Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        return format!("panicked: {}", message);
+    }
+    if let Some(message) = payload.downcast_ref::<String>() {
+        return format!("panicked: {}", message);
+    }
+    String::from("panicked")
+}
+
+// Exit codes.
+// Errors before any target was attempted, such as invalid arguments, exit with 1.
+const EXIT_TARGETS_FAILED: u8 = 2;
+// A second SIGINT or SIGTERM exits at once, as if killed by SIGINT.
+const EXIT_SIGNALLED: i32 = 130;
+
+fn failed_result(num_failed: usize, num_targets: usize) -> ExitCode {
+    if num_failed > 0 {
+        eprintln!("Error: {} of {} targets failed", num_failed, num_targets);
+        return ExitCode::from(EXIT_TARGETS_FAILED);
+    }
+    ExitCode::SUCCESS
+}
+
+// Targets of `map` in `order`.
+fn ordered_targets(
+    map: &HashMap<PathBuf, Vec<PathBuf>>,
+    order: Order,
+    decompress: Decompress,
+) -> Vec<&PathBuf> {
+    let mut keys = map.keys().collect::<Vec<_>>();
+    keys.sort_unstable();
+    if let Order::Size = order {
+        // A fragment that cannot be stat'd makes the size unknown rather than an error here.
+        keys.sort_by_cached_key(|key| {
+            let size = estimated_size(&map[*key], decompress).ok().flatten();
+            std::cmp::Reverse(size)
         });
-        leaf_tasks.push(task);
     }
+    keys
+}
 
-    // Do sectoin tasks.
-    loop {
-        if leaf_tasks.len() <= 1 {
-            break;
+// Size of the file reconstructed from `fragments`, from their size suffix or on disk.
+// None if a compressed fragment has no size suffix.
+fn estimated_size(fragments: &[PathBuf], decompress: Decompress) -> std::io::Result<Option<u64>> {
+    let mut total = 0;
+    for fragment in fragments {
+        total += match (fragment_size(fragment), decompress) {
+            (Some(size), _) => size,
+            (None, Decompress::None) => std::fs::metadata(fragment)
+                .map_err(|e| {
+                    std::io::Error::new(e.kind(), format!("{}: {}", fragment.display(), e))
+                })?
+                .len(),
+            (None, _) => return Ok(None),
+        };
+    }
+    Ok(Some(total))
+}
+
+// Directory `output` is written to, without creating it.
+fn output_dir(output: &Path, config: &Config) -> PathBuf {
+    // A destination that cannot be told fails on its own, its size is still counted.
+    let destination = destination_path(output, config).unwrap_or_else(|_| output.to_path_buf());
+    match destination.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+// Bytes available in `dir`, or in its nearest existing ancestor if it is yet to be created.
+fn free_space(dir: &Path) -> std::io::Result<u64> {
+    for ancestor in dir.ancestors().filter(|a| !a.as_os_str().is_empty()) {
+        match fs2::available_space(ancestor) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            result => return result,
         }
-        let mut temp_tasks: Vec<Task> = Vec::new();
-        leaf_tasks.reverse();
+    }
+    // A relative path with no existing ancestor.
+    fs2::available_space(".")
+}
 
-        loop {
-            let mut task = Task::new();
-            let mut child_tasks: Vec<Task> = Vec::new();
+// Fragments found under the root directory or read from stdin, by the file they reconstruct.
+// Targets with missing fragments are added to `outcomes` as failed instead.
+fn scan(
+    args: &Args,
+    outcomes: &mut Vec<Outcome>,
+    skipped: &mut Vec<Skipped>,
+) -> Result<HashMap<PathBuf, Vec<PathBuf>>, Box<dyn std::error::Error>> {
+    let extension = args.config.decompress.extension();
+    let re = match (args.layout, &args.fragment_regex) {
+        (Layout::Dir, _) => dir_pattern(extension)?,
+        (Layout::Suffix, Some(suffix)) => suffix_pattern(suffix, extension)?,
+        (Layout::Suffix, None) => fragment_pattern(&args.delimiter, extension)?,
+    };
 
-            for _ in 0..num_cat_once {
-                let t = leaf_tasks.pop().unwrap_or_else(Task::new);
-                task.files
-                    .push(t.files.first().unwrap_or(&String::from("")).clone());
-                child_tasks.push(t);
-            }
-            let files = task.files.to_vec();
-            task.handler = std::thread::spawn(move || {
-                for i in child_tasks {
-                    i.handler.join().unwrap();
+    // Find files to reconstruct, with their paths relative to the root.
+    let paths: Vec<(PathBuf, PathBuf)> = if args.stdin {
+        // Byte for byte, a file name need not be UTF-8.
+        let lines = std::io::stdin()
+            .lock()
+            .split(b'\n')
+            .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+            .collect::<Result<Vec<_>, _>>()?;
+        lines
+            .into_iter()
+            .filter_map(|line| {
+                let Some(path) = path_from_bytes(&line) else {
+                    log::warn!("Skipping {}: not a path", String::from_utf8_lossy(&line));
+                    return None;
+                };
+                // e.g. DIR/sub/a.txt.FRAG-00000 -> sub/a.txt.FRAG-00000
+                let path = normalize_path(&path);
+                let relative = path
+                    .strip_prefix(normalize_path(&args.root_dir))
+                    .unwrap_or(&path)
+                    .to_path_buf();
+                Some((path, relative))
+            })
+            .collect()
+    } else {
+        let root_dir = &args.root_dir;
+        let root_metadata = std::fs::metadata(root_dir)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", root_dir.display(), e)))?;
+        if !root_metadata.is_dir() {
+            let dir_error =
+                std::io::Error::other(format!("{} is not a directory", root_dir.display()));
+            return Err(Box::new(dir_error));
+        }
+
+        VisitDir::new(root_dir, args.scan)?
+            .filter_map(|e| match e {
+                // Beside fragments in their own directories, directories are expected.
+                Ok(e) if e.is_dir && args.layout == Layout::Dir => None,
+                Ok(e) => Some((Path::new(root_dir).join(&e.path), e.path)),
+                Err(error) => {
+                    log::warn!("Skipping a directory entry: {}", error);
+                    None
                 }
-                loop {
-                    match cat(&files) {
-                        Ok(_) => break,
-                        Err(error) => {
-                            log::debug!(
-                                "Error: {}. Retrying in 5 secs. Leader = {}",
-                                error,
-                                files[0]
-                            );
-                            std::thread::sleep(std::time::Duration::from_secs(6));
-                        }
+            })
+            .collect()
+    };
+    let include = glob_set(&args.include)?;
+    let exclude = glob_set(&args.exclude)?;
+    let paths = paths
+        .into_iter()
+        .filter(|(path, relative)| {
+            re.is_match(path.as_os_str().as_encoded_bytes())
+                && (args.include.is_empty() || include.is_match(relative))
+                && !exclude.is_match(relative)
+                // The root is never a target, it would replace the whole tree.
+                && (args.layout != Layout::Dir
+                    || relative.parent().is_some_and(|p| !p.as_os_str().is_empty()))
+        })
+        .map(|(path, _)| path)
+        .filter(|path| is_regular_file(path));
+    let mut map = group_fragments(paths, &re);
+    if let Some((after, time)) = &args.after {
+        keep_modified_after(&mut map, *after, time, skipped);
+    }
+
+    // Every file in the directory is to be a fragment, see --layout.
+    if args.layout == Layout::Dir {
+        map.retain(|key, val| {
+            let message = match first_unnumbered(key, val) {
+                Ok(None) => return true,
+                Ok(Some(path)) => format!("{} is not numbered as a fragment", path.display()),
+                Err(error) => format!("cannot list {}: {}", key.display(), error),
+            };
+            log::error!("Not reconstructing {}. {}", key.display(), message);
+            outcomes.push(Outcome {
+                target: key.clone(),
+                fragments: val.len(),
+                elapsed: Duration::ZERO,
+                result: Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message).into()),
+            });
+            false
+        });
+    }
+
+    // Both copies of a fragment would end up in the output.
+    map.retain(|key, val| {
+        let duplicates = duplicate_fragments(&re, val);
+        let Some((first, second)) = duplicates.first() else {
+            return true;
+        };
+        log::error!(
+            "Not reconstructing {}. {} and {} have the same number",
+            key.display(),
+            first.display(),
+            second.display()
+        );
+        outcomes.push(Outcome {
+            target: key.clone(),
+            fragments: val.len(),
+            elapsed: Duration::ZERO,
+            result: Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "duplicate fragments: {} and {}",
+                    first.display(),
+                    second.display()
+                ),
+            )
+            .into()),
+        });
+        false
+    });
+
+    // A missing fragment would silently produce a corrupted file.
+    if !args.allow_gaps {
+        // A declared number of fragments also catches the last ones missing.
+        map.retain(|key, val| {
+            let val = &with_journaled(key, val, &args.config);
+            let message = match declared_totals(&re, val)[..] {
+                [] => return true,
+                [total] if total == val.len() as u64 => return true,
+                [total] => {
+                    let missing = missing_indices(&re, val);
+                    let mut message = format!("expected {} fragments, found {}", total, val.len());
+                    if !missing.is_empty() {
+                        let missing = missing.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+                        message.push_str(&format!(", missing {}", missing.join(", ")));
                     }
+                    message
                 }
-                //cat(&files).unwrap();
+                ref totals => {
+                    let totals = totals
+                        .iter()
+                        .map(|t| t.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    format!("fragments disagree on their number: {}", totals)
+                }
+            };
+            log::error!("Not reconstructing {}. {}", key.display(), message);
+            outcomes.push(Outcome {
+                target: key.clone(),
+                fragments: val.len(),
+                elapsed: Duration::ZERO,
+                result: Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message).into()),
             });
-            temp_tasks.push(task);
+            false
+        });
 
-            if leaf_tasks.is_empty() {
-                break;
+        map.retain(|key, val| {
+            let missing = missing_indices(&re, &with_journaled(key, val, &args.config));
+            if missing.is_empty() {
+                return true;
             }
+            let missing = missing
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            log::error!(
+                "Not reconstructing {}. Missing fragments: {}",
+                key.display(),
+                missing
+            );
+            outcomes.push(Outcome {
+                target: key.clone(),
+                fragments: val.len(),
+                elapsed: Duration::ZERO,
+                result: Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("missing fragments: {}", missing),
+                )
+                .into()),
+            });
+            false
+        });
+    }
+
+    Ok(map)
+}
+
+// The first entry of the directory `dir` that is not one of `fragments`, if any.
+fn first_unnumbered(dir: &Path, fragments: &[PathBuf]) -> std::io::Result<Option<PathBuf>> {
+    let names = fragments
+        .iter()
+        .filter_map(|f| f.file_name())
+        .collect::<HashSet<_>>();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if !names.contains(entry.file_name().as_os_str()) {
+            return Ok(Some(entry.path()));
         }
+    }
+    Ok(None)
+}
 
-        assert_eq!(leaf_tasks.len(), 0);
-        leaf_tasks.append(&mut temp_tasks);
+// `fragments` of `output` and those an interrupted run appended to one of them,
+// which count as present.
+fn with_journaled(output: &Path, fragments: &[PathBuf], config: &Config) -> Vec<PathBuf> {
+    let mut all = fragments.to_vec();
+    let present = fragments.iter().collect::<HashSet<_>>();
+    match journal_of(output, config).and_then(|journal| journal.fragments()) {
+        Ok(journaled) => all.extend(journaled.into_iter().filter(|f| !present.contains(f))),
+        Err(error) => log::warn!("Cannot read the journal of {}: {}", output.display(), error),
     }
+    all
+}
 
-    let last_task = leaf_tasks.pop().unwrap();
-    assert_eq!(leaf_tasks.len(), 0);
+// One set matching any of `globs`.
+fn glob_set(globs: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob)?);
+    }
+    builder.build()
+}
 
-    // Make sure last task has been finished.
-    last_task.handler.join().unwrap();
+// Concatenate the fragments of every target of `map` from the last to the first, for
+// --reverse-order.
+fn reverse_fragments(map: &mut HashMap<PathBuf, Vec<PathBuf>>) {
+    for fragments in map.values_mut() {
+        fragments.reverse();
+    }
+}
 
-    // Rename vsi_traverse_-s--l-0.txt.FRAG-00000
-    // e.g. rename vsi_traverse_-s--l-0.txt.FRAG-00000 to vsi_traverse_-s--l-0.txt
-    let long_filename = last_task.files.first().unwrap().clone();
-    let short_filename = file.clone();
-    std::fs::rename(&long_filename, &short_filename).unwrap();
+// `map` with every target renamed by --name-transform.
+// Targets that would end up with the same name are added to `outcomes` as failed instead.
+fn transform_names(
+    map: HashMap<PathBuf, Vec<PathBuf>>,
+    re: &Regex,
+    replacement: &str,
+    outcomes: &mut Vec<Outcome>,
+) -> HashMap<PathBuf, Vec<PathBuf>> {
+    let mut renamed: HashMap<PathBuf, Vec<(PathBuf, Vec<PathBuf>)>> = HashMap::new();
+    for (key, val) in map {
+        let name = re.replace(key.as_os_str().as_encoded_bytes(), replacement.as_bytes());
+        let Some(name) = path_from_bytes(&name) else {
+            log::error!(
+                "Not reconstructing {}. Renamed to an invalid path",
+                key.display()
+            );
+            outcomes.push(Outcome {
+                target: key,
+                fragments: val.len(),
+                elapsed: Duration::ZERO,
+                result: Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--name-transform made an invalid path",
+                )
+                .into()),
+            });
+            continue;
+        };
+        renamed.entry(name).or_default().push((key, val));
+    }
 
-    log::info!("End reconstruction of {}", file);
+    let mut transformed = HashMap::new();
+    for (name, mut targets) in renamed {
+        if targets.len() == 1 {
+            let (key, val) = targets.pop().unwrap();
+            log::debug!("Reconstructing {} as {}", key.display(), name.display());
+            transformed.insert(name, val);
+            continue;
+        }
+        targets.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let keys = targets
+            .iter()
+            .map(|(key, _)| key.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(", ");
+        log::error!(
+            "Not reconstructing {}. All would be named {}",
+            keys,
+            name.display()
+        );
+        for (key, val) in targets {
+            outcomes.push(Outcome {
+                target: key,
+                fragments: val.len(),
+                elapsed: Duration::ZERO,
+                result: Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{} is also the name of another target", name.display()),
+                )
+                .into()),
+            });
+        }
+    }
+    transformed
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    parse_args()?;
-    env_logger::init();
+// Targets of `map` that would be written to the same destination as another one,
+// or over a fragment, are added to `outcomes` as failed instead, before anything is moved.
+// Existing files about to be overwritten are warned about.
+fn check_collisions(
+    map: &mut HashMap<PathBuf, Vec<PathBuf>>,
+    config: &Config,
+    outcomes: &mut Vec<Outcome>,
+) {
+    let fragments = map.values().flatten().collect::<HashSet<_>>();
+    let mut writers: HashMap<PathBuf, Vec<&PathBuf>> = HashMap::new();
+    // A target without a destination fails on its own when reconstructed.
+    for key in map.keys() {
+        if let Ok(destination) = destination_path(key, config) {
+            writers.entry(destination).or_default().push(key);
+        }
+    }
 
-    log::debug!("NUM_CAT_ONCE = {}", NUM_CAT_ONCE.lock()?);
+    let mut colliding = Vec::new();
+    for (destination, mut keys) in writers {
+        keys.sort_unstable();
+        let reason = if keys.len() > 1 {
+            let keys = keys
+                .iter()
+                .map(|key| key.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(
+                "{} would be written by all of {}",
+                destination.display(),
+                keys
+            )
+        } else if fragments.contains(&destination) {
+            format!("{} is a fragment of another target", destination.display())
+        } else {
+            // With --layout dir, the directory holding the fragments is replaced anyway.
+            let holder = map[keys[0]]
+                .iter()
+                .all(|f| f.parent() == Some(destination.as_path()));
+            if std::fs::symlink_metadata(&destination).is_ok()
+                && matches!(config.existing, Existing::Overwrite)
+                && !holder
+            {
+                log::warn!(
+                    "{} already exists and will be overwritten",
+                    destination.display()
+                );
+            }
+            continue;
+        };
+        log::error!("Not reconstructing {}. {}", keys[0].display(), reason);
+        colliding.extend(keys.into_iter().map(|key| (key.clone(), reason.clone())));
+    }
 
-    let re = Regex::new(r".FRAG-")?;
-    let timer = std::time::Instant::now();
+    for (key, reason) in colliding {
+        let fragments = map.remove(&key).map_or(0, |val| val.len());
+        outcomes.push(Outcome {
+            target: key,
+            fragments,
+            elapsed: Duration::ZERO,
+            result: Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, reason).into()),
+        });
+    }
+}
+
+// Every target of `map` with only its fragments at positions in `range`, for
+// --fragment-range, renamed after the range, e.g. a.txt -> a.txt.range-0-10.
+// Targets without any are added to `skipped`.
+fn select_range(
+    map: HashMap<PathBuf, Vec<PathBuf>>,
+    range: &std::ops::Range<usize>,
+    skipped: &mut Vec<Skipped>,
+) -> HashMap<PathBuf, Vec<PathBuf>> {
+    let mut selected = HashMap::new();
+    for (key, mut val) in map {
+        if range.start >= val.len() {
+            log::info!(
+                "Skipping {}. Only {} fragments, none in --fragment-range.",
+                key.display(),
+                val.len()
+            );
+            skipped.push(Skipped {
+                target: key,
+                fragments: val.len(),
+                reason: SkipReason::OutsideFragmentRange,
+            });
+            continue;
+        }
+        let end = range.end.min(val.len());
+        val.truncate(end);
+        val.drain(..range.start);
+        let mut name = key.clone().into_os_string();
+        name.push(format!(".range-{}-{}", range.start, end));
+        log::debug!("Reconstructing {} as {:?}", key.display(), name);
+        selected.insert(PathBuf::from(name), val);
+    }
+    selected
+}
+
+// The fragments of every target of `map` as they are concatenated, reversed for
+// --reverse-order and then cut down to --fragment-range, whose positions count in
+// that order.
+fn concatenation_order(
+    mut map: HashMap<PathBuf, Vec<PathBuf>>,
+    args: &Args,
+    skipped: &mut Vec<Skipped>,
+) -> HashMap<PathBuf, Vec<PathBuf>> {
+    if args.reverse_order {
+        reverse_fragments(&mut map);
+    }
+    if let Some(range) = &args.fragment_range {
+        map = select_range(map, range, skipped);
+    }
+    map
+}
 
-    // Find files to reconstruct.
-    let paths = VisitDir::new(".")?
-        .filter_map(|e| Some(e.ok()?.path().to_string_lossy().into_owned()))
-        .filter(|s| re.is_match(s))
+// Keep only the target of `map` and `outcomes` named `name` by --only.
+// It is an error unless exactly one target matches.
+fn only_target(
+    map: &mut HashMap<PathBuf, Vec<PathBuf>>,
+    outcomes: &mut Vec<Outcome>,
+    name: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // e.g. sub/a.txt names ./sub/a.txt but not ./other_sub/a.txt
+    let is_named = |key: &Path| key == name || key.ends_with(name);
+    let mut targets = map
+        .keys()
+        .map(PathBuf::as_path)
+        .chain(outcomes.iter().map(|o| o.target.as_path()))
+        .collect::<Vec<_>>();
+    targets.sort_unstable();
+    let list = |keys: &[&Path]| {
+        keys.iter()
+            .map(|key| key.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let matching = targets
+        .iter()
+        .copied()
+        .filter(|key| is_named(key))
         .collect::<Vec<_>>();
+    let message = match matching.len() {
+        1 => None,
+        0 => Some(format!(
+            "--only {}: no such target. Available targets: {}",
+            name.display(),
+            list(&targets)
+        )),
+        _ => Some(format!(
+            "--only {}: matches several targets: {}",
+            name.display(),
+            list(&matching)
+        )),
+    };
+    if let Some(message) = message {
+        return Err(Box::new(std::io::Error::other(message)));
+    }
+    map.retain(|key, _| is_named(key));
+    outcomes.retain(|o| is_named(&o.target));
+    Ok(())
+}
 
-    let mut map: std::collections::HashMap<String, Vec<String>> = std::collections::HashMap::new();
-    for i in paths.iter() {
-        let file: String = i.split(".FRAG-").next().unwrap().to_string();
-        map.entry(file)
-            .and_modify(|files| files.push(i.to_string()))
-            .or_insert_with(|| vec![i.to_string()]);
+// Targets recorded in the --resume state file, one per line, byte for byte.
+// A missing state file records nothing.
+fn read_state(state: &Path) -> std::io::Result<HashSet<PathBuf>> {
+    match std::fs::read(state) {
+        Ok(content) => Ok(content
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .filter_map(path_from_bytes)
+            .map(|target| normalize_path(&target))
+            .collect()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(error) => Err(error),
     }
+}
 
-    let mut join_handler = Vec::new();
+// Reconstruct `target` from `fragments`, recording it in the --resume `state` once done.
+// Returns the result and how long it took.
+// Reconstruct `targets` of `map` in order, at most `drivers` at a time, each on a
+// thread that waits for its tasks on the worker pool. A target that panics fails
+// alone. Returns every target with its number of fragments and how it ended, in order.
+fn reconstruct_all(
+    reconstructor: &Reconstructor,
+    map: &HashMap<PathBuf, Vec<PathBuf>>,
+    targets: &[&PathBuf],
+    drivers: usize,
+    state: Option<&Mutex<std::fs::File>>,
+    log_format: LogFormat,
+) -> Vec<Finished> {
+    let next = AtomicUsize::new(0);
+    let mut finished = std::thread::scope(|scope| {
+        let drivers = (0..drivers.min(targets.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut finished = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(&target) = targets.get(i) else {
+                            return finished;
+                        };
+                        let fragments = &map[target];
+                        let done = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                            reconstruct_target(reconstructor, target, fragments, state, log_format)
+                        }))
+                        .unwrap_or_else(|payload| {
+                            let error = std::io::Error::other(panic_message(&payload));
+                            (Err(error.into()), Duration::ZERO)
+                        });
+                        finished.push((i, (target.clone(), fragments.len(), done)));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drivers
+            .into_iter()
+            .flat_map(|driver| driver.join().expect("panics are caught"))
+            .collect::<Vec<_>>()
+    });
+    finished.sort_unstable_by_key(|&(i, _)| i);
+    finished.into_iter().map(|(_, finished)| finished).collect()
+}
+
+// A target with its number of fragments, how its reconstruction ended and how long it took.
+type Finished = (
+    PathBuf,
+    usize,
+    (Result<Reconstructed, ReconstructError>, Duration),
+);
+
+fn reconstruct_target(
+    reconstructor: &Reconstructor,
+    target: &Path,
+    fragments: &[PathBuf],
+    state: Option<&Mutex<std::fs::File>>,
+    log_format: LogFormat,
+) -> (Result<Reconstructed, ReconstructError>, Duration) {
+    let started = Instant::now();
+    let result = reconstructor.reconstruct(target, fragments);
+    if log_format == LogFormat::Oneline {
+        report::log_oneline(target, fragments.len(), &result, started.elapsed());
+    }
+    // Record the target as soon as it is done, a later one may never finish.
+    if let (Ok(_), Some(state)) = (&result, state) {
+        if let Err(error) = record_state(state, target) {
+            log::warn!("Failed to record {} as done: {}", target.display(), error);
+        }
+    }
+    (result, started.elapsed())
+}
 
-    for (key, val) in &mut map {
-        val.sort_unstable();
-        let key_copy = key.clone();
-        let val_copy = val.to_vec();
-        let handler = std::thread::spawn(move || {
-            reconstruct(&key_copy, &val_copy);
+// Leave out the fragments not modified after `after`, given as `time`, for --after.
+// Targets left without fragments are added to `skipped`. Those with fragments on both
+// sides of `after` are warned about, most likely an old set was partly overwritten.
+fn keep_modified_after(
+    map: &mut HashMap<PathBuf, Vec<PathBuf>>,
+    after: SystemTime,
+    time: &str,
+    skipped: &mut Vec<Skipped>,
+) {
+    map.retain(|key, fragments| {
+        let found = fragments.len();
+        // A fragment whose time cannot be read is kept and fails later if it is unreadable.
+        fragments.retain(|fragment| {
+            std::fs::metadata(fragment)
+                .and_then(|metadata| metadata.modified())
+                .map_or(true, |modified| modified > after)
         });
-        join_handler.push(handler);
+        match fragments.len() {
+            0 => {
+                log::info!(
+                    "Skipping {}. No fragment modified after {}.",
+                    key.display(),
+                    time
+                );
+                skipped.push(Skipped {
+                    target: key.clone(),
+                    fragments: found,
+                    reason: SkipReason::NotModifiedAfter,
+                });
+                false
+            }
+            kept if kept < found => {
+                log::warn!(
+                    "{} of {} fragments of {} were modified before {} and are left out",
+                    found - kept,
+                    found,
+                    key.display(),
+                    time
+                );
+                true
+            }
+            _ => true,
+        }
+    });
+}
+
+// LOCK_FILE in a tree, locked until dropped, which also removes it.
+struct TreeLock {
+    path: PathBuf,
+    // Unlocked when closed, after the file is removed.
+    _file: std::fs::File,
+}
+
+impl Drop for TreeLock {
+    fn drop(&mut self) {
+        // Still locked, so a run that opened it in the meantime finds it was removed
+        // once it gets the lock, and starts over with a new one.
+        if let Err(error) = std::fs::remove_file(&self.path) {
+            log::debug!("Cannot remove {}: {}", self.path.display(), error);
+        }
+    }
+}
+
+// Lock LOCK_FILE in `dir` until the returned lock is dropped, so that two runs over
+// the same tree do not race on its fragments. Fails if another run holds it.
+// A tree the lock file cannot be created in, e.g. a read-only one, is not guarded.
+fn lock_tree(dir: &Path) -> Result<Option<TreeLock>, Box<dyn std::error::Error>> {
+    use fs2::FileExt;
+
+    let path = dir.join(LOCK_FILE);
+    loop {
+        let file = match std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+        {
+            Ok(file) => file,
+            Err(error) => {
+                log::warn!(
+                    "Cannot create {}: {}. Another run over {} is not kept out.",
+                    path.display(),
+                    error,
+                    dir.display()
+                );
+                return Ok(None);
+            }
+        };
+        match file.try_lock_exclusive() {
+            // Removed by the run that held it before.
+            Ok(()) if !is_open_at(&file, &path) => continue,
+            Ok(()) => return Ok(Some(TreeLock { path, _file: file })),
+            Err(error) if error.kind() == fs2::lock_contended_error().kind() => {
+                return Err(Box::new(std::io::Error::other(format!(
+                    "{} is locked, another mtreconstruct is running over {}. \
+                    Wait for it to finish, or pass --no-lock if it works on other files.",
+                    path.display(),
+                    dir.display()
+                ))));
+            }
+            Err(error) => {
+                return Err(Box::new(std::io::Error::new(
+                    error.kind(),
+                    format!("Cannot lock {}: {}", path.display(), error),
+                )));
+            }
+        }
+    }
+}
+
+// Whether `file` is still what `path` names.
+// Where an open file cannot be removed, as on Windows, it always is.
+fn is_open_at(file: &std::fs::File, path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (file.metadata(), std::fs::symlink_metadata(path)) {
+            (Ok(open), Ok(named)) => open.dev() == named.dev() && open.ino() == named.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (file, path);
+        true
+    }
+}
+
+// Whether `path` is a regular file or a symbolic link to one.
+// Anything else named like a fragment, e.g. a directory or a FIFO, is logged.
+fn is_regular_file(path: &Path) -> bool {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => true,
+        Ok(metadata) => {
+            log::warn!(
+                "Skipping {}. It is named like a fragment but is {}.",
+                path.display(),
+                file_kind(&metadata.file_type())
+            );
+            false
+        }
+        Err(error) => {
+            log::warn!("Skipping {}: {}", path.display(), error);
+            false
+        }
     }
+}
 
-    for i in join_handler {
-        i.join().unwrap();
+// e.g. "a directory"
+fn file_kind(file_type: &std::fs::FileType) -> &'static str {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            return "a FIFO";
+        }
+        if file_type.is_socket() {
+            return "a socket";
+        }
+        if file_type.is_block_device() || file_type.is_char_device() {
+            return "a device";
+        }
     }
+    if file_type.is_dir() {
+        "a directory"
+    } else {
+        "not a regular file"
+    }
+}
+
+// Append `target` to the --resume state file.
+fn record_state(state: &Mutex<std::fs::File>, target: &Path) -> std::io::Result<()> {
+    let mut file = state
+        .lock()
+        .map_err(|_| std::io::Error::other("state file lock poisoned"))?;
+    let mut line = target.as_os_str().as_encoded_bytes().to_vec();
+    line.push(b'\n');
+    file.write_all(&line)?;
+    file.sync_data()
+}
 
-    log::info!(
-        "Reconstruction completed. Elapsed {} ms",
-        timer.elapsed().as_millis()
+// Fragments listed in `manifest`, by the file they reconstruct.
+// Targets with a missing or differently sized fragment are added to `outcomes` as failed instead.
+fn read_manifest(
+    manifest: &Path,
+    outcomes: &mut Vec<Outcome>,
+) -> Result<HashMap<PathBuf, Vec<PathBuf>>, Box<dyn std::error::Error>> {
+    let mut map = HashMap::new();
+    for (key, entries) in manifest::read(manifest)? {
+        if let Err(error) = manifest::check_sizes(&entries) {
+            log::error!("Not reconstructing {}. {}", key.display(), error);
+            outcomes.push(Outcome {
+                target: key,
+                fragments: entries.len(),
+                elapsed: Duration::ZERO,
+                result: Err(error.into()),
+            });
+            continue;
+        }
+        map.insert(key, entries.into_iter().map(|e| e.path).collect());
+    }
+    Ok(map)
+}
+
+// `split` subcommand: cut every FILE into fragments next to it, the inverse of
+// reconstructing them. FILE is left as it is.
+fn split_files() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let command_line = CommandLine::new();
+    let args = &command_line.args;
+    let program = args[0].clone();
+    let mut opts = getopts::Options::new();
+    opts.optopt(
+        "b",
+        "chunk-size",
+        "Bytes per fragment with an optional K, M or G suffix, e.g. 64M. The last one holds the rest.",
+        "SIZE",
     );
-    Ok(())
+    opts.optopt(
+        "",
+        "delimiter",
+        "String separating the file name from the fragment number. Default is .FRAG-",
+        "DELIMITER",
+    );
+    opts.optflag("h", "help", "Print this message.");
+
+    let matches = opts.parse(&args[2..])?;
+    if matches.opt_present("h") {
+        let brief = format!(
+            "Split files into fragments named FILE.FRAG-00000, FILE.FRAG-00001, ...
+Usage: {program} split --chunk-size SIZE FILE..."
+        );
+        print!("{}", opts.usage(&brief));
+        return Ok(ExitCode::SUCCESS);
+    }
+    let Some(chunk_size_arg) = matches.opt_str("chunk-size") else {
+        return Err(Box::new(std::io::Error::other("split needs --chunk-size")));
+    };
+    let chunk_size = parse_size(&chunk_size_arg)? as u64;
+    let delimiter = matches
+        .opt_str("delimiter")
+        .unwrap_or_else(|| String::from(DELIMITER_DEFAULT));
+    if delimiter.is_empty() {
+        return Err(Box::new(std::io::Error::other(
+            "Delimiter must not be empty",
+        )));
+    }
+    if matches.free.is_empty() {
+        return Err(Box::new(std::io::Error::other("split needs a FILE")));
+    }
+
+    for file in matches.free {
+        mtreconstruct::split::split(&command_line.path(file), chunk_size, &delimiter)?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    // A directory named split is given as ./split.
+    if env::args_os().nth(1).is_some_and(|arg| arg == "split") {
+        return split_files();
+    }
+    let args = parse_args()?;
+    if args.self_test {
+        if selftest::run(&args.config)? {
+            return Ok(ExitCode::SUCCESS);
+        }
+        eprintln!("Error: self-test failed");
+        return Ok(ExitCode::from(EXIT_TARGETS_FAILED));
+    }
+
+    log::debug!("NUM_CAT_ONCE = {}", args.config.num_cat_once);
+
+    // Held until the end of the run. Listing and planning touch nothing, and
+    // neither does writing to stdout, which leaves the fragments in place.
+    let _lock = if args.lock && !args.list_targets && !args.dry_run && !args.to_stdout {
+        lock_tree(&args.root_dir)?
+    } else {
+        None
+    };
+
+    let timer = std::time::Instant::now();
+    let mut outcomes = Vec::new();
+    let mut skipped = Vec::new();
+    let mut map = match &args.manifest {
+        Some(manifest) => read_manifest(manifest, &mut outcomes)?,
+        None => scan(&args, &mut outcomes, &mut skipped)?,
+    };
+    if let Some((re, replacement)) = &args.name_transform {
+        map = transform_names(map, re, replacement, &mut outcomes);
+    }
+    if let Some(name) = &args.only {
+        only_target(&mut map, &mut outcomes, name)?;
+    }
+    map = concatenation_order(map, &args, &mut skipped);
+    map.retain(|key, fragments| {
+        let skip = fragments.len() < args.min_fragments;
+        if skip {
+            log::info!(
+                "Skipping {}. Only {} fragments.",
+                key.display(),
+                fragments.len()
+            );
+            skipped.push(Skipped {
+                target: key.clone(),
+                fragments: fragments.len(),
+                reason: SkipReason::TooFewFragments,
+            });
+        }
+        !skip
+    });
+
+    // Targets finished by an earlier run.
+    let state = match &args.resume {
+        Some(state) => {
+            let done = read_state(state)?;
+            map.retain(|key, fragments| {
+                let skip = done.contains(key);
+                if skip {
+                    log::info!("Skipping {}. Already reconstructed.", key.display());
+                    skipped.push(Skipped {
+                        target: key.clone(),
+                        fragments: fragments.len(),
+                        reason: SkipReason::AlreadyReconstructed,
+                    });
+                }
+                !skip
+            });
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(state)?;
+            Some(Arc::new(Mutex::new(file)))
+        }
+        None => None,
+    };
+    check_collisions(&mut map, &args.config, &mut outcomes);
+    let num_targets = map.len() + outcomes.len();
+
+    // Only show what would be reconstructed.
+    if args.list_targets {
+        for key in ordered_targets(&map, args.order, args.config.decompress) {
+            println!("{}\t{}", key.display(), map[key].len());
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Only show what would be done.
+    if args.dry_run {
+        let mut total = 0;
+        // Bytes and targets going to each output directory.
+        let mut needed: HashMap<PathBuf, (u64, usize)> = HashMap::new();
+        let mut num_failed = outcomes.len();
+        for key in ordered_targets(&map, args.order, args.config.decompress) {
+            let val = &map[key];
+            match estimated_size(val, args.config.decompress)? {
+                Some(bytes) => {
+                    println!(
+                        "{} ({} fragments, {} bytes)",
+                        key.display(),
+                        val.len(),
+                        bytes
+                    );
+                    total += bytes;
+                    let dir = output_dir(key, &args.config);
+                    let (dir_bytes, dir_targets) = needed.entry(dir).or_default();
+                    *dir_bytes += bytes;
+                    *dir_targets += 1;
+                }
+                None => println!("{} ({} fragments, size unknown)", key.display(), val.len()),
+            }
+            for fragment in val {
+                println!("    {}", fragment.display());
+            }
+        }
+        println!("Total: {} targets, {} bytes", map.len(), total);
+
+        let mut needed = needed.into_iter().collect::<Vec<_>>();
+        needed.sort_unstable();
+        for (dir, (bytes, targets)) in needed {
+            let free = free_space(&dir)?;
+            println!(
+                "{}: {} bytes needed, {} bytes free",
+                dir.display(),
+                bytes,
+                free
+            );
+            if bytes > free {
+                log::error!(
+                    "Not enough space in {}: {} bytes needed, {} bytes free",
+                    dir.display(),
+                    bytes,
+                    free
+                );
+                num_failed += targets;
+            }
+        }
+        return Ok(failed_result(num_failed, num_targets));
+    }
+
+    // Stream the one target instead of reconstructing it.
+    if args.to_stdout {
+        let (Some((key, fragments)), 1) = (map.iter().next(), num_targets) else {
+            let message = format!(
+                "--to-stdout needs exactly one target, found {}",
+                num_targets
+            );
+            return Err(Box::new(std::io::Error::other(message)));
+        };
+        let mut stdout =
+            std::io::BufWriter::with_capacity(args.config.buffer_size, std::io::stdout().lock());
+        match cat_to(fragments, &mut stdout, &args.config) {
+            Ok(bytes) => log::info!("Wrote {} bytes of {} to stdout", bytes, key.display()),
+            // The reader has seen enough, e.g. head.
+            Err(error) if error.kind() == std::io::ErrorKind::BrokenPipe => {
+                log::debug!("Stopped writing {} to stdout: {}", key.display(), error);
+            }
+            Err(error) => return Err(Box::new(error)),
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let reconstructor = Arc::new(Reconstructor::new(args.config));
+    log::debug!("JOBS = {}", reconstructor.config().jobs);
+    // The first SIGINT or SIGTERM lets the running concatenations finish, the second exits.
+    let stop_flag = reconstructor.stop_flag();
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(
+            signal,
+            EXIT_SIGNALLED,
+            Arc::clone(stop_flag),
+        )?;
+        signal_hook::flag::register(signal, Arc::clone(stop_flag))?;
+    }
+    let (stop_progress, stop) = mpsc::channel();
+    let reporter = args.progress.then(|| {
+        let reconstructor = Arc::clone(&reconstructor);
+        std::thread::spawn(move || report_progress(&reconstructor, stop))
+    });
+
+    // No more targets at once than tasks can run, the others would only wait.
+    let drivers = match reconstructor.config().sequential {
+        true => 1,
+        false => reconstructor.config().jobs,
+    };
+    let targets = ordered_targets(&map, args.order, reconstructor.config().decompress);
+    let finished = reconstruct_all(
+        &reconstructor,
+        &map,
+        &targets,
+        drivers,
+        state.as_deref(),
+        args.log_format,
+    );
+    for (key, fragments, (result, elapsed)) in finished {
+        match &result {
+            Err(error) => log::error!("Failed to reconstruct {}: {}", key.display(), error),
+            // Left alone as the output exists, see Existing::Skip.
+            Ok(reconstructed) if reconstructed.fragments == 0 && fragments > 0 => {
+                skipped.push(Skipped {
+                    target: key,
+                    fragments,
+                    reason: SkipReason::OutputExists,
+                });
+                continue;
+            }
+            Ok(_) => {}
+        }
+        outcomes.push(Outcome {
+            target: key,
+            fragments,
+            elapsed,
+            result,
+        });
+    }
+
+    if let Some(reporter) = reporter {
+        let _ = stop_progress.send(());
+        let _ = reporter.join();
+    }
+    if stop_flag.load(Ordering::Relaxed) {
+        log::warn!("Stopped by a signal. Unfinished targets can be reconstructed by another run.");
+    }
+
+    outcomes.sort_by(|a, b| a.target.cmp(&b.target));
+    skipped.sort_by(|a, b| a.target.cmp(&b.target));
+    report::log_summary(&outcomes, &skipped, timer.elapsed());
+    if args.json {
+        report::print_json(&outcomes, &skipped, timer.elapsed());
+    }
+    if let Some(metrics_file) = &args.metrics_file {
+        report::write_metrics(metrics_file, &outcomes, timer.elapsed())?;
+    }
+
+    let num_failed = outcomes.iter().filter(|o| o.result.is_err()).count();
+    Ok(failed_result(num_failed, num_targets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan_dir(dir: &Path) -> HashMap<PathBuf, Vec<PathBuf>> {
+        let args = Args {
+            root_dir: dir.to_path_buf(),
+            ..Args::default()
+        };
+        scan(&args, &mut Vec::new(), &mut Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn fragment_range_counts_after_reverse_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        for i in 0..5 {
+            std::fs::write(dir.join(format!("a.FRAG-{:05}", i)), i.to_string()).unwrap();
+        }
+
+        let args = Args {
+            reverse_order: true,
+            fragment_range: Some(1..3),
+            ..Args::default()
+        };
+        let mut skipped = Vec::new();
+        let map = concatenation_order(scan_dir(dir), &args, &mut skipped);
+        assert!(skipped.is_empty());
+        assert_eq!(
+            map[&dir.join("a.range-1-3")],
+            [dir.join("a.FRAG-00003"), dir.join("a.FRAG-00002")]
+        );
+    }
+
+    #[test]
+    fn scan_skips_directories_named_like_fragments() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        std::fs::create_dir(dir.join("x.FRAG-00000")).unwrap();
+        std::fs::write(dir.join("x.FRAG-00000").join("notes.txt"), b"").unwrap();
+        std::fs::write(dir.join("a.FRAG-00000"), b"0").unwrap();
+
+        let map = scan_dir(dir);
+        assert_eq!(map[&dir.join("a")], [dir.join("a.FRAG-00000")]);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn reverse_order_concatenates_the_last_fragment_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("a");
+        let chunks: [&[u8]; 3] = [b"first", b"second", b"third"];
+        let paths = chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut fragment = output.as_os_str().to_owned();
+                fragment.push(format!("{}{:05}", DELIMITER_DEFAULT, i));
+                std::fs::write(&fragment, chunk).unwrap();
+                PathBuf::from(fragment)
+            })
+            .collect::<Vec<_>>();
+
+        let mut map = group_fragments(paths, &fragment_pattern(DELIMITER_DEFAULT, "").unwrap());
+        reverse_fragments(&mut map);
+        let reconstructed = mtreconstruct::reconstruct(&output, &map[&output], &Config::default());
+        assert_eq!(reconstructed.unwrap().fragments, 3);
+        assert_eq!(std::fs::read(&output).unwrap(), b"thirdsecondfirst");
+    }
+
+    #[test]
+    fn rfc3339_secs_rejects_days_the_month_does_not_have() {
+        assert_eq!(rfc3339_secs("2024-05-01T12:00:00Z"), Some(1714564800.0));
+        assert_eq!(rfc3339_secs("2024-02-29T00:00:00Z"), Some(1709164800.0));
+        assert_eq!(rfc3339_secs("2000-02-29T00:00:00Z"), Some(951782400.0));
+        for time in [
+            "2023-02-29T00:00:00Z",
+            "1900-02-29T00:00:00Z",
+            "2024-02-30T00:00:00Z",
+            "2024-04-31T00:00:00Z",
+            "2024-13-01T00:00:00Z",
+        ] {
+            assert_eq!(rfc3339_secs(time), None, "{}", time);
+        }
+    }
+
+    #[test]
+    fn layout_dir_rejects_a_directory_with_a_file_that_is_not_a_fragment() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        for target in ["a.txt", "b.txt"] {
+            std::fs::create_dir(dir.join(target)).unwrap();
+            std::fs::write(dir.join(target).join("FRAG-00000"), b"0").unwrap();
+            std::fs::write(dir.join(target).join("FRAG-00001"), b"1").unwrap();
+        }
+        std::fs::write(dir.join("b.txt").join("2024-notes.txt"), b"").unwrap();
+
+        let args = Args {
+            root_dir: dir.to_path_buf(),
+            layout: Layout::Dir,
+            ..Args::default()
+        };
+        let mut outcomes = Vec::new();
+        let map = scan(&args, &mut outcomes, &mut Vec::new()).unwrap();
+        assert_eq!(
+            map[&dir.join("a.txt")],
+            [dir.join("a.txt/FRAG-00000"), dir.join("a.txt/FRAG-00001")]
+        );
+        assert_eq!(map.len(), 1);
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].target, dir.join("b.txt"));
+        assert!(outcomes[0].result.is_err());
+    }
+
+    #[test]
+    fn lock_tree_keeps_out_a_second_run_and_removes_the_lock_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = lock_tree(dir.path()).unwrap().unwrap();
+        assert!(lock_tree(dir.path()).is_err());
+        drop(lock);
+        assert!(!dir.path().join(LOCK_FILE).exists());
+        assert!(lock_tree(dir.path()).unwrap().is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_line_gives_back_paths_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let mut command_line = CommandLine {
+            args: Vec::new(),
+            originals: Vec::new(),
+        };
+        let dir = OsString::from_vec(b"dir\xff".to_vec());
+        for (arg, option) in [
+            (b"dir\xff".to_vec(), ""),
+            (b"--output-dir=dir\xff".to_vec(), "--output-dir="),
+            (b"-odir\xff".to_vec(), "-o"),
+        ] {
+            let placeholder = command_line.placeholder(OsString::from_vec(arg));
+            let value = placeholder.strip_prefix(option).unwrap().to_string();
+            assert_eq!(command_line.path(value), PathBuf::from(&dir));
+        }
+        assert_eq!(command_line.path(String::from("a.txt")), Path::new("a.txt"));
+    }
+
+    #[test]
+    fn reconstruct_all_finishes_every_target_in_order_with_fewer_drivers() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        for target in 0..5 {
+            for i in 0..3 {
+                let fragment = dir.join(format!("{}.FRAG-{:05}", target, i));
+                std::fs::write(fragment, i.to_string()).unwrap();
+            }
+        }
+
+        let map = scan_dir(dir);
+        let mut targets = map.keys().collect::<Vec<_>>();
+        targets.sort();
+        let reconstructor = Reconstructor::new(Config::default());
+        let finished = reconstruct_all(&reconstructor, &map, &targets, 2, None, LogFormat::Default);
+        assert_eq!(finished.len(), 5);
+        for ((target, fragments, (result, _)), expected) in finished.iter().zip(&targets) {
+            assert_eq!(&target, expected);
+            assert_eq!(*fragments, 3);
+            assert_eq!(result.as_ref().unwrap().fragments, 3);
+            assert_eq!(std::fs::read(target).unwrap(), b"012");
+        }
+    }
+
+    #[test]
+    fn transform_names_renames_targets_and_fails_those_that_collide() {
+        let map = HashMap::from([
+            (
+                PathBuf::from("a-1.txt"),
+                vec![PathBuf::from("a-1.txt.FRAG-00000")],
+            ),
+            (
+                PathBuf::from("b-1.txt"),
+                vec![PathBuf::from("b-1.txt.FRAG-00000")],
+            ),
+            (
+                PathBuf::from("b-2.txt"),
+                vec![PathBuf::from("b-2.txt.FRAG-00000")],
+            ),
+        ]);
+        let re = Regex::new(r"-\d+").unwrap();
+        let mut outcomes = Vec::new();
+        let map = transform_names(map, &re, "", &mut outcomes);
+        assert_eq!(map.len(), 1);
+        assert_eq!(
+            map[Path::new("a.txt")],
+            [PathBuf::from("a-1.txt.FRAG-00000")]
+        );
+        let mut failed = outcomes.iter().map(|o| &o.target).collect::<Vec<_>>();
+        failed.sort();
+        assert_eq!(failed, [Path::new("b-1.txt"), Path::new("b-2.txt")]);
+    }
+
+    #[test]
+    fn check_collisions_fails_targets_with_the_same_destination() {
+        let mut map = HashMap::from([
+            (
+                PathBuf::from("x/a.txt"),
+                vec![PathBuf::from("x/a.txt.FRAG-00000")],
+            ),
+            (
+                PathBuf::from("y/a.txt"),
+                vec![PathBuf::from("y/a.txt.FRAG-00000")],
+            ),
+            (
+                PathBuf::from("y/b.txt"),
+                vec![PathBuf::from("y/b.txt.FRAG-00000")],
+            ),
+            // Written over a fragment of b.txt.
+            (PathBuf::from("y/b.txt.FRAG-00000"), Vec::new()),
+        ]);
+        let config = Config {
+            output_dir: Some(PathBuf::from("y")),
+            ..Config::default()
+        };
+        let mut outcomes = Vec::new();
+        check_collisions(&mut map, &config, &mut outcomes);
+        assert_eq!(map.len(), 1);
+        assert!(map.contains_key(Path::new("y/b.txt")));
+        let mut failed = outcomes.iter().map(|o| &o.target).collect::<Vec<_>>();
+        failed.sort();
+        assert_eq!(
+            failed,
+            [
+                Path::new("x/a.txt"),
+                Path::new("y/a.txt"),
+                Path::new("y/b.txt.FRAG-00000")
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_modified_after_leaves_out_older_fragments() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let after = SystemTime::now() - Duration::from_secs(3600);
+        let old = after - Duration::from_secs(60);
+        for (name, modified) in [
+            ("a.FRAG-00000", old),
+            ("a.FRAG-00001", SystemTime::now()),
+            ("b.FRAG-00000", old),
+        ] {
+            let file = std::fs::File::create(dir.join(name)).unwrap();
+            file.set_modified(modified).unwrap();
+        }
+
+        let mut map = scan_dir(dir);
+        let mut skipped = Vec::new();
+        keep_modified_after(&mut map, after, "an hour ago", &mut skipped);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map[&dir.join("a")], [dir.join("a.FRAG-00001")]);
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].target, dir.join("b"));
+    }
 }
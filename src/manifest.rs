@@ -0,0 +1,161 @@
+//! Fragments listed explicitly instead of found by scanning a directory.
+//!
+//! A manifest has one fragment per line, `<output>\t<fragment>[\t<size>]`.
+//! The fragments of an output are concatenated in the order they are listed.
+//! Relative paths are relative to the directory of the manifest.
+//! Empty lines and lines starting with `#` are ignored.
+
+use crate::{normalize_path, path_from_bytes};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+/// One fragment listed in a manifest.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Path of the fragment.
+    pub path: PathBuf,
+    /// Expected size of the fragment on disk, if listed.
+    pub size: Option<u64>,
+}
+
+/// Fragments listed in the manifest at `path`, grouped by the file they reconstruct.
+/// Paths are taken byte for byte, so they need not be UTF-8.
+pub fn read(path: &Path) -> io::Result<HashMap<PathBuf, Vec<Entry>>> {
+    let base = path.parent().unwrap_or(Path::new(""));
+    let reader = io::BufReader::new(fs::File::open(path)?);
+    let mut map: HashMap<PathBuf, Vec<Entry>> = HashMap::new();
+
+    for (number, line) in reader.split(b'\n').enumerate() {
+        let line = line?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        if line.trim_ascii().is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        let invalid = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), number + 1, message),
+            )
+        };
+
+        let fields = line.split(|&b| b == b'\t').collect::<Vec<_>>();
+        let (output, fragment, size) = match fields[..] {
+            [output, fragment] => (output, fragment, None),
+            [output, fragment, size] => (output, fragment, Some(size)),
+            _ => {
+                return Err(invalid(String::from(
+                    "expected <output>\\t<fragment>[\\t<size>]",
+                )))
+            }
+        };
+        if output.is_empty() || fragment.is_empty() {
+            return Err(invalid(String::from("empty path")));
+        }
+        let size = match size {
+            Some(size) => {
+                let size = String::from_utf8_lossy(size);
+                Some(
+                    size.trim()
+                        .parse()
+                        .map_err(|e| invalid(format!("invalid size {:?}: {}", size, e)))?,
+                )
+            }
+            None => None,
+        };
+        let (Some(output), Some(fragment)) = (path_from_bytes(output), path_from_bytes(fragment))
+        else {
+            return Err(invalid(String::from("path is not valid on this platform")));
+        };
+
+        map.entry(normalize_path(&base.join(output)))
+            .or_default()
+            .push(Entry {
+                path: normalize_path(&base.join(fragment)),
+                size,
+            });
+    }
+    Ok(map)
+}
+
+/// Check that every fragment exists and has the size listed.
+pub fn check_sizes(entries: &[Entry]) -> io::Result<()> {
+    for entry in entries {
+        let actual = fs::metadata(&entry.path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", entry.path.display(), e)))?
+            .len();
+        match entry.size {
+            Some(size) if size != actual => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{}: expected {} bytes, found {}",
+                        entry.path.display(),
+                        size,
+                        actual
+                    ),
+                ));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_groups_the_fragments_in_the_order_listed() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("manifest.tsv");
+        fs::write(
+            &manifest,
+            "# output\tfragment\tsize\n\
+            a.txt\tparts/second\t3\r\n\
+            \n\
+            a.txt\tparts/first\n\
+            /abs/b.txt\t/abs/b.part\n",
+        )
+        .unwrap();
+
+        let map = read(&manifest).unwrap();
+        assert_eq!(map.len(), 2);
+        let a = &map[&dir.path().join("a.txt")];
+        assert_eq!(a[0].path, dir.path().join("parts/second"));
+        assert_eq!(a[0].size, Some(3));
+        assert_eq!(a[1].path, dir.path().join("parts/first"));
+        assert_eq!(a[1].size, None);
+        assert_eq!(
+            map[Path::new("/abs/b.txt")][0].path,
+            Path::new("/abs/b.part")
+        );
+    }
+
+    #[test]
+    fn read_fails_on_a_line_without_a_fragment() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("manifest.tsv");
+        fs::write(&manifest, "a.txt\ta.part\nb.txt\n").unwrap();
+        let error = read(&manifest).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains(":2:"), "{}", error);
+    }
+
+    #[test]
+    fn check_sizes_compares_with_the_size_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.part");
+        fs::write(&path, b"abc").unwrap();
+        let entry = |size| Entry {
+            path: path.clone(),
+            size,
+        };
+        assert!(check_sizes(&[entry(Some(3)), entry(None)]).is_ok());
+        assert!(check_sizes(&[entry(Some(4))]).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(check_sizes(&[entry(None)]).is_err());
+    }
+}
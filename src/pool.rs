@@ -0,0 +1,78 @@
+//! Fixed number of worker threads running submitted jobs in order.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct WorkerPool {
+    sender: Option<mpsc::Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Start `size` workers. At least one worker is started.
+    pub fn new(size: usize) -> WorkerPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                std::thread::spawn(move || loop {
+                    let job = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return,
+                    };
+                    match job {
+                        // A panicking job must not take the worker down with it.
+                        // Whoever waits for the job notices it never finished.
+                        Ok(job) => {
+                            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                        }
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+        WorkerPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// No workers at all. Every job runs on the thread submitting it, before
+    /// `execute` returns, so that jobs run one at a time in the order submitted.
+    pub fn inline() -> WorkerPool {
+        WorkerPool {
+            sender: None,
+            workers: Vec::new(),
+        }
+    }
+
+    /// Queue `job` to run on the next free worker.
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match &self.sender {
+            // The workers only stop once the sender is dropped.
+            Some(sender) => sender.send(Box::new(job)).unwrap(),
+            // Only dropped before any worker was started, in an inline pool.
+            None => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    // Finish the queued jobs before returning.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
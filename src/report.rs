@@ -0,0 +1,369 @@
+// Reporting the outcome of a run.
+
+use mtreconstruct::{ReconstructError, Reconstructed};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// What happened to one target.
+pub struct Outcome {
+    pub target: PathBuf,
+    pub fragments: usize,
+    pub elapsed: Duration,
+    pub result: Result<Reconstructed, ReconstructError>,
+}
+
+// A target left alone on purpose, which is neither reconstructed nor failed.
+pub struct Skipped {
+    pub target: PathBuf,
+    pub fragments: usize,
+    pub reason: SkipReason,
+}
+
+// Why a target was skipped.
+#[derive(Clone, Copy)]
+pub enum SkipReason {
+    // Fewer fragments than --min-fragments.
+    TooFewFragments,
+    // No fragment modified after --after.
+    NotModifiedAfter,
+    // Recorded as done by an earlier run, see --resume.
+    AlreadyReconstructed,
+    // The output exists, see --skip.
+    OutputExists,
+    // No fragment in --fragment-range.
+    OutsideFragmentRange,
+}
+
+impl SkipReason {
+    // e.g. too_few_fragments, for JSON.
+    fn name(self) -> &'static str {
+        match self {
+            SkipReason::TooFewFragments => "too_few_fragments",
+            SkipReason::NotModifiedAfter => "not_modified_after",
+            SkipReason::AlreadyReconstructed => "already_reconstructed",
+            SkipReason::OutputExists => "output_exists",
+            SkipReason::OutsideFragmentRange => "outside_fragment_range",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            SkipReason::TooFewFragments => "fewer fragments than --min-fragments",
+            SkipReason::NotModifiedAfter => "no fragment modified after --after",
+            SkipReason::AlreadyReconstructed => "reconstructed by an earlier run, see --resume",
+            SkipReason::OutputExists => "the output already exists, see --skip",
+            SkipReason::OutsideFragmentRange => "no fragment in --fragment-range",
+        }
+    }
+}
+
+// Sums over all outcomes, shared by every report.
+struct Totals {
+    targets: usize,
+    failed: usize,
+    fragments: usize,
+    bytes: u64,
+    bytes_copied: u64,
+}
+
+impl Totals {
+    fn of(outcomes: &[Outcome]) -> Totals {
+        let (bytes, bytes_copied) = outcomes
+            .iter()
+            .filter_map(|o| o.result.as_ref().ok())
+            .fold((0, 0), |(b, c), r| (b + r.bytes, c + r.bytes_copied));
+        Totals {
+            targets: outcomes.len(),
+            failed: outcomes.iter().filter(|o| o.result.is_err()).count(),
+            fragments: outcomes.iter().map(|o| o.fragments).sum(),
+            bytes,
+            bytes_copied,
+        }
+    }
+}
+
+// Print all outcomes, the skipped targets and the totals to stdout as one JSON object.
+// e.g. {"targets":[{"target":"a.txt",...,"status":"ok"}],"skipped":[],"summary":{"targets":1,...}}
+pub fn print_json(outcomes: &[Outcome], skipped: &[Skipped], elapsed: Duration) {
+    let targets = outcomes
+        .iter()
+        .map(|outcome| {
+            let mut fields = vec![
+                format!(
+                    "\"target\":{}",
+                    json_string(&outcome.target.to_string_lossy())
+                ),
+                format!("\"fragments\":{}", outcome.fragments),
+            ];
+            match &outcome.result {
+                Ok(reconstructed) => {
+                    let output = reconstructed.path.to_string_lossy();
+                    fields.push(format!("\"output\":{}", json_string(&output)));
+                    fields.push(format!("\"bytes\":{}", reconstructed.bytes));
+                    fields.push(format!("\"bytes_copied\":{}", reconstructed.bytes_copied));
+                    if let Some(crc) = reconstructed.crc32 {
+                        fields.push(format!("\"crc32\":\"{:08x}\"", crc));
+                    }
+                    fields.push(String::from("\"status\":\"ok\""));
+                }
+                Err(error) => {
+                    fields.push(String::from("\"status\":\"failed\""));
+                    fields.push(format!("\"error\":{}", json_string(&error.to_string())));
+                }
+            }
+            fields.push(format!("\"elapsed_ms\":{}", outcome.elapsed.as_millis()));
+            format!("{{{}}}", fields.join(","))
+        })
+        .collect::<Vec<_>>();
+    let skipped_targets = skipped
+        .iter()
+        .map(|skipped| {
+            format!(
+                "{{\"target\":{},\"fragments\":{},\"reason\":\"{}\"}}",
+                json_string(&skipped.target.to_string_lossy()),
+                skipped.fragments,
+                skipped.reason.name()
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let totals = Totals::of(outcomes);
+    let summary = format!(
+        "{{\"targets\":{},\"succeeded\":{},\"failed\":{},\"skipped\":{},\"fragments\":{},\"bytes\":{},\"elapsed_ms\":{}}}",
+        totals.targets,
+        totals.targets - totals.failed,
+        totals.failed,
+        skipped.len(),
+        totals.fragments,
+        totals.bytes,
+        elapsed.as_millis()
+    );
+
+    println!(
+        "{{\"targets\":[{}],\"skipped\":[{}],\"summary\":{}}}",
+        targets.join(","),
+        skipped_targets.join(","),
+        summary
+    );
+}
+
+// Log the elapsed time, a table of the outcomes with their throughput, the overall one,
+// why each failed target failed and why each skipped one was skipped.
+pub fn log_summary(outcomes: &[Outcome], skipped: &[Skipped], elapsed: Duration) {
+    log::info!(
+        "Reconstruction completed. Elapsed {} ms",
+        elapsed.as_millis()
+    );
+    log::info!(
+        "{:<40} {:>9} {:>14} {:>14} {:>10} {:>8}",
+        "TARGET",
+        "FRAGMENTS",
+        "BYTES",
+        "BYTES COPIED",
+        "MS",
+        "MB/S"
+    );
+    for outcome in outcomes {
+        let (bytes, bytes_copied, throughput) = match &outcome.result {
+            Ok(r) => (
+                r.bytes.to_string(),
+                r.bytes_copied.to_string(),
+                format!("{:.1}", megabytes_per_sec(r.bytes_copied, outcome.elapsed)),
+            ),
+            Err(_) => (String::from("failed"), String::from("-"), String::from("-")),
+        };
+        log::info!(
+            "{:<40} {:>9} {:>14} {:>14} {:>10} {:>8}",
+            outcome.target.display().to_string(),
+            outcome.fragments,
+            bytes,
+            bytes_copied,
+            outcome.elapsed.as_millis(),
+            throughput
+        );
+    }
+
+    let totals = Totals::of(outcomes);
+    log::info!(
+        "Total: {} targets, {} fragments, {} bytes written, {} bytes copied, {:.1} MB/s",
+        totals.targets,
+        totals.fragments,
+        totals.bytes,
+        totals.bytes_copied,
+        megabytes_per_sec(totals.bytes_copied, elapsed)
+    );
+    for outcome in outcomes {
+        if let Err(error) = &outcome.result {
+            log::error!("{}: {}", outcome.target.display(), error);
+        }
+    }
+    if !skipped.is_empty() {
+        log::info!("Skipped:");
+        for skipped in skipped {
+            log::info!(
+                "  {}: {}",
+                skipped.target.display(),
+                skipped.reason.description()
+            );
+        }
+    }
+}
+
+// Log one line summing up a target as it finishes, for --log-format oneline.
+// e.g. target=sub/a.txt fragments=3 bytes=1024 elapsed_ms=5 status=ok
+pub fn log_oneline(
+    target: &Path,
+    fragments: usize,
+    result: &Result<Reconstructed, ReconstructError>,
+    elapsed: Duration,
+) {
+    let target = logfmt_value(&target.to_string_lossy());
+    let elapsed_ms = elapsed.as_millis();
+    match result {
+        // Left alone, e.g. as the output exists.
+        Ok(reconstructed) if reconstructed.fragments == 0 && fragments > 0 => log::info!(
+            "target={} fragments={} elapsed_ms={} status=skipped",
+            target,
+            fragments,
+            elapsed_ms
+        ),
+        Ok(reconstructed) => log::info!(
+            "target={} fragments={} bytes={} elapsed_ms={} status=ok",
+            target,
+            fragments,
+            reconstructed.bytes,
+            elapsed_ms
+        ),
+        Err(error) => log::error!(
+            "target={} fragments={} elapsed_ms={} status=failed error={}",
+            target,
+            fragments,
+            elapsed_ms,
+            logfmt_value(&error.to_string())
+        ),
+    }
+}
+
+// Write all outcomes and their totals to `path` in the Prometheus text format.
+// The file is replaced at once so that a scraper never reads half of it.
+pub fn write_metrics(path: &Path, outcomes: &[Outcome], elapsed: Duration) -> io::Result<()> {
+    let totals = Totals::of(outcomes);
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        text.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, kind
+        ));
+        for (labels, value) in samples {
+            text.push_str(&format!("{}{} {}\n", name, labels, value));
+        }
+    };
+    let total = |value: String| vec![(String::new(), value)];
+    metric(
+        "mtreconstruct_targets_total",
+        "counter",
+        "Targets found.",
+        total(totals.targets.to_string()),
+    );
+    metric(
+        "mtreconstruct_targets_failed",
+        "gauge",
+        "Targets not reconstructed.",
+        total(totals.failed.to_string()),
+    );
+    metric(
+        "mtreconstruct_bytes_total",
+        "counter",
+        "Bytes written to reconstructed files.",
+        total(totals.bytes.to_string()),
+    );
+    metric(
+        "mtreconstruct_duration_seconds",
+        "gauge",
+        "Duration of the run.",
+        total(elapsed.as_secs_f64().to_string()),
+    );
+
+    // e.g. {target="sub/a.txt"}
+    let per_target = |value: &dyn Fn(&Outcome) -> String| {
+        outcomes
+            .iter()
+            .map(|o| {
+                let labels = format!(
+                    "{{target=\"{}\"}}",
+                    label_value(&o.target.to_string_lossy())
+                );
+                (labels, value(o))
+            })
+            .collect::<Vec<_>>()
+    };
+    metric(
+        "mtreconstruct_target_fragments",
+        "gauge",
+        "Fragments of the target.",
+        per_target(&|o| o.fragments.to_string()),
+    );
+    metric(
+        "mtreconstruct_target_bytes",
+        "gauge",
+        "Bytes written to the target, 0 if it failed.",
+        per_target(&|o| o.result.as_ref().map_or(0, |r| r.bytes).to_string()),
+    );
+    metric(
+        "mtreconstruct_target_duration_seconds",
+        "gauge",
+        "Time spent reconstructing the target.",
+        per_target(&|o| o.elapsed.as_secs_f64().to_string()),
+    );
+    metric(
+        "mtreconstruct_target_failed",
+        "gauge",
+        "1 if the target was not reconstructed, else 0.",
+        per_target(&|o| u8::from(o.result.is_err()).to_string()),
+    );
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)
+}
+
+fn megabytes_per_sec(bytes: u64, elapsed: Duration) -> f64 {
+    bytes as f64 / 1_000_000.0 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+// `s` escaped for a Prometheus label value.
+fn label_value(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// `s` as a logfmt value, quoted if it is empty or has spaces, quotes or equal signs.
+fn logfmt_value(s: &str) -> String {
+    if !s.is_empty() && !s.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        return String::from(s);
+    }
+    json_string(s)
+}
+
+// `s` as a quoted JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,155 @@
+//! Walking a directory tree for fragments.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::{self, DirEntry};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// How `VisitDir` walks a directory tree.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScanOptions {
+    /// Descend into symbolic links to directories.
+    /// Directories already visited are skipped to avoid loops.
+    pub follow_symlinks: bool,
+    /// How many levels of subdirectories to descend into.
+    /// 0 means only the root, `None` means no limit.
+    pub max_depth: Option<usize>,
+    /// Yield and descend into entries whose name starts with a dot, e.g. .git.
+    /// The root is walked either way.
+    pub include_hidden: bool,
+}
+
+/// Something found below the root of a `VisitDir`.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Path relative to the root.
+    /// e.g. sub/vsi_traverse_-s--l-0.txt.FRAG-00000
+    pub path: PathBuf,
+    /// Number of directories between the root and the entry, 0 directly in the root.
+    pub depth: usize,
+    /// The entry is a directory or a symbolic link to one.
+    pub is_dir: bool,
+}
+
+/// Recursive iterator over the entries below a directory.
+///
+/// Only an unreadable root is an error. Subdirectories that cannot be read
+/// are logged and skipped.
+pub struct VisitDir {
+    root: Box<dyn Iterator<Item = io::Result<Entry>>>,
+    children: Box<dyn Iterator<Item = VisitDir>>,
+}
+
+// Canonical paths of the directories visited so far.
+type Visited = Rc<RefCell<HashSet<PathBuf>>>;
+
+impl VisitDir {
+    pub fn new<P: AsRef<Path>>(path: P, options: ScanOptions) -> io::Result<Self> {
+        let visited = Visited::default();
+        if options.follow_symlinks {
+            visited.borrow_mut().insert(fs::canonicalize(&path)?);
+        }
+        VisitDir::visit(path.as_ref(), PathBuf::new(), 0, options, visited)
+    }
+
+    // `relative` is `path` relative to the root, `depth` the number of
+    // directories between them.
+    fn visit(
+        path: &Path,
+        relative: PathBuf,
+        depth: usize,
+        options: ScanOptions,
+        visited: Visited,
+    ) -> io::Result<Self> {
+        let parent = relative.clone();
+        let include_hidden = options.include_hidden;
+        let shown = move |e: &io::Result<DirEntry>| {
+            include_hidden || e.as_ref().map_or(true, |e| !is_hidden(&e.file_name()))
+        };
+        let root = Box::new(fs::read_dir(path)?.filter(shown).map(move |e| {
+            let e = e?;
+            Ok(Entry {
+                path: parent.join(e.file_name()),
+                depth,
+                is_dir: is_dir(&e),
+            })
+        }));
+        if options
+            .max_depth
+            .is_some_and(|max_depth| depth >= max_depth)
+        {
+            let children = Box::new(std::iter::empty());
+            return Ok(VisitDir { root, children });
+        }
+        let follow_symlinks = options.follow_symlinks;
+        // Errors on the entries themselves are yielded by root.
+        let children = Box::new(fs::read_dir(path)?.filter(shown).filter_map(move |e| {
+            let e = e.ok()?;
+            let file_type = e.file_type().ok()?;
+            if file_type.is_symlink() {
+                if !follow_symlinks || !fs::metadata(e.path()).ok()?.is_dir() {
+                    return None;
+                }
+            } else if !file_type.is_dir() {
+                return None;
+            }
+            if follow_symlinks {
+                let canonical = fs::canonicalize(e.path()).ok()?;
+                if !visited.borrow_mut().insert(canonical) {
+                    log::debug!("Skipping {}: already visited", e.path().display());
+                    return None;
+                }
+            }
+            let child = VisitDir::visit(
+                &e.path(),
+                relative.join(e.file_name()),
+                depth + 1,
+                options,
+                Rc::clone(&visited),
+            );
+            match child {
+                Ok(child) => Some(child),
+                Err(error) => {
+                    log::warn!("Skipping {}: {}", e.path().display(), error);
+                    None
+                }
+            }
+        }));
+        Ok(VisitDir { root, children })
+    }
+
+    fn entries(self) -> Box<dyn Iterator<Item = io::Result<Entry>>> {
+        Box::new(self.root.chain(self.children.flat_map(|s| s.entries())))
+    }
+}
+
+impl Iterator for VisitDir {
+    type Item = io::Result<Entry>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(item) = self.root.next() {
+            return Some(item);
+        }
+        if let Some(child) = self.children.next() {
+            self.root = child.entries();
+            return self.next();
+        }
+        None
+    }
+}
+
+// e.g. .git, .cache. read_dir never yields . and ..
+fn is_hidden(name: &OsStr) -> bool {
+    name.as_encoded_bytes().starts_with(b".")
+}
+
+// Only symbolic links need another look at the file system.
+fn is_dir(e: &DirEntry) -> bool {
+    match e.file_type() {
+        Ok(file_type) if file_type.is_symlink() => e.path().is_dir(),
+        Ok(file_type) => file_type.is_dir(),
+        Err(_) => false,
+    }
+}
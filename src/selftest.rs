@@ -0,0 +1,120 @@
+// Splitting files of random content and sizes into fragments in a temporary
+// directory and checking that they are reconstructed byte for byte.
+
+use mtreconstruct::split::fragment_path;
+use mtreconstruct::{Config, Decompress, Existing, Reconstructor, DELIMITER_DEFAULT};
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::Path;
+
+// Largest fragment written, in bytes.
+const MAX_FRAGMENT_SIZE: usize = 4096;
+// Most fragments of one case, so that a large NUMBER does not fill the disk.
+const MAX_FRAGMENTS: usize = 10_000;
+
+// Run every case with `config` and print its result, except that the fragments
+// are neither compressed nor written elsewhere. Returns whether all of them passed.
+// The temporary directory is removed unless a case failed.
+pub fn run(config: &Config) -> io::Result<bool> {
+    let config = Config {
+        decompress: Decompress::None,
+        output_dir: None,
+        existing: Existing::Error,
+        ..config.clone()
+    };
+    let n = config.num_cat_once;
+    // Fewer, as many as and more fragments than one task concatenates,
+    // so that the tree has one, two and three levels.
+    // Saturating, the counts above MAX_FRAGMENTS are dropped anyway.
+    let mut counts = vec![
+        1,
+        2,
+        n - 1,
+        n,
+        n.saturating_add(1),
+        n.saturating_mul(2).saturating_add(1),
+        n.saturating_mul(n).saturating_add(3),
+    ];
+    counts.retain(|&count| count <= MAX_FRAGMENTS);
+    counts.sort_unstable();
+    counts.dedup();
+
+    let dir = std::env::temp_dir().join(format!("mtreconstruct-test-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let mut random = Random::new();
+    let reconstructor = Reconstructor::new(config);
+    let mut passed = true;
+    for (case, count) in counts.into_iter().enumerate() {
+        let output = dir.join(format!("case{}.bin", case));
+        let original = split(&output, count, &mut random)?;
+        let fragments = (0..count)
+            .map(|i| fragment_path(&output, DELIMITER_DEFAULT, i))
+            .collect::<Vec<_>>();
+        let result = reconstructor
+            .reconstruct(&output, &fragments)
+            .map_err(io::Error::from)
+            .and_then(|reconstructed| fs::read(reconstructed.path));
+        match result {
+            Ok(content) if content == original => {
+                println!("ok: {} fragments, {} bytes", count, original.len());
+            }
+            Ok(_) => {
+                println!(
+                    "FAILED: {} fragments, {} bytes: {} differs from the original",
+                    count,
+                    original.len(),
+                    output.display()
+                );
+                passed = false;
+            }
+            Err(error) => {
+                println!("FAILED: {} fragments: {}", count, error);
+                passed = false;
+            }
+        }
+    }
+
+    if passed {
+        fs::remove_dir_all(&dir)?;
+    } else {
+        println!("Fragments and outputs are left in {}", dir.display());
+    }
+    Ok(passed)
+}
+
+// Write `count` fragments of random sizes, some empty, for `output`.
+// Returns the content they make together.
+fn split(output: &Path, count: usize, random: &mut Random) -> io::Result<Vec<u8>> {
+    let mut original = Vec::new();
+    for i in 0..count {
+        let size = random.below(MAX_FRAGMENT_SIZE as u64 + 1) as usize;
+        let fragment = (0..size).map(|_| random.next() as u8).collect::<Vec<_>>();
+        fs::write(fragment_path(output, DELIMITER_DEFAULT, i), &fragment)?;
+        original.extend(fragment);
+    }
+    Ok(original)
+}
+
+// xorshift64, randomly seeded. Good enough for test data.
+struct Random(u64);
+
+impl Random {
+    fn new() -> Random {
+        // The state must not be 0.
+        Random(RandomState::new().build_hasher().finish() | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // Number in 0..bound.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
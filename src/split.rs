@@ -0,0 +1,110 @@
+//! The inverse of reconstruction: cutting a file into fragments named the way
+//! they are scanned for, e.g. a.txt.FRAG-00000, a.txt.FRAG-00001, ...
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Fragment `index` of `output`, e.g. a.txt -> a.txt.FRAG-00003 for 3 and `.FRAG-`.
+/// The index is padded to five digits, more are kept as they are.
+pub fn fragment_path(output: &Path, delimiter: &str, index: usize) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(format!("{}{:05}", delimiter, index));
+    PathBuf::from(path)
+}
+
+/// Cut `input` into fragments of `chunk_size` bytes next to it, the last one
+/// holding the rest. An empty file makes one empty fragment.
+/// `input` is left as it is, and no fragment that exists already is replaced.
+/// Returns the fragments in order.
+pub fn split(input: &Path, chunk_size: u64, delimiter: &str) -> io::Result<Vec<PathBuf>> {
+    if chunk_size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "chunk size must not be 0",
+        ));
+    }
+    let file = fs::File::open(input)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", input.display(), e)))?;
+    let len = file.metadata()?.len();
+    let mut reader = io::BufReader::new(file);
+
+    let mut fragments = Vec::new();
+    loop {
+        let fragment = fragment_path(input, delimiter, fragments.len());
+        let mut writer = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&fragment)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", fragment.display(), e)))?;
+        let copied = io::copy(&mut (&mut reader).take(chunk_size), &mut writer)?;
+        writer.flush()?;
+        fragments.push(fragment);
+        // No empty fragment after a last one that is full.
+        if copied < chunk_size || fragments.len() as u64 * chunk_size >= len {
+            break;
+        }
+    }
+    Ok(fragments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_cuts_chunks_that_make_the_file_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("a.bin");
+        let content = (0..10u8).collect::<Vec<_>>();
+        fs::write(&input, &content).unwrap();
+
+        let fragments = split(&input, 4, ".FRAG-").unwrap();
+        assert_eq!(
+            fragments,
+            (0..3)
+                .map(|i| fragment_path(&input, ".FRAG-", i))
+                .collect::<Vec<_>>()
+        );
+        let sizes = fragments
+            .iter()
+            .map(|f| fs::metadata(f).unwrap().len())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [4, 4, 2]);
+        let joined = fragments.iter().flat_map(|f| fs::read(f).unwrap());
+        assert_eq!(joined.collect::<Vec<_>>(), content);
+        assert_eq!(fs::read(&input).unwrap(), content);
+    }
+
+    #[test]
+    fn split_makes_no_empty_fragment_after_a_full_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("a.bin");
+        fs::write(&input, [0; 8]).unwrap();
+        assert_eq!(split(&input, 4, ".FRAG-").unwrap().len(), 2);
+
+        let empty = dir.path().join("empty.bin");
+        fs::write(&empty, b"").unwrap();
+        let fragments = split(&empty, 4, ".FRAG-").unwrap();
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fs::metadata(&fragments[0]).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn split_replaces_no_fragment() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("a.bin");
+        fs::write(&input, [0; 8]).unwrap();
+        let existing = fragment_path(&input, ".FRAG-", 1);
+        fs::write(&existing, b"kept").unwrap();
+        assert_eq!(
+            split(&input, 4, ".FRAG-").unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(fs::read(&existing).unwrap(), b"kept");
+        assert_eq!(
+            split(&input, 0, ".FRAG-").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}